@@ -1,12 +1,12 @@
 //! Implements support for the pallet_ibc module.
-use codec::Encode;
+use codec::{Decode, Encode};
 use core::marker::PhantomData;
 use sp_core::H256;
 use substrate_subxt::{
     balances::{Balances, BalancesEventsDecoder},
     module,
     system::{System, SystemEventsDecoder},
-    Call, Store,
+    Call, Event, Store,
 };
 
 /// The subset of the `pallet_ibc::Trait` that a client must implement.
@@ -60,3 +60,30 @@ pub struct SubmitDatagramCall<T: Ibc> {
     pub _runtime: PhantomData<T>,
     pub datagram: pallet_ibc::Datagram,
 }
+
+/// A packet was sent on a channel.
+#[derive(Clone, Debug, Decode, Eq, Event, PartialEq)]
+pub struct SendPacketEvent<T: Ibc> {
+    pub sequence: u64,
+    pub data: Vec<u8>,
+    pub timeout_height: u32,
+    pub source_port: Vec<u8>,
+    pub source_channel: H256,
+    pub dest_port: Vec<u8>,
+    pub dest_channel: H256,
+    pub _runtime: PhantomData<T>,
+}
+
+/// A packet was received from a channel and acknowledged.
+#[derive(Clone, Debug, Decode, Eq, Event, PartialEq)]
+pub struct RecvPacketEvent<T: Ibc> {
+    pub sequence: u64,
+    pub data: Vec<u8>,
+    pub timeout_height: u32,
+    pub source_port: Vec<u8>,
+    pub source_channel: H256,
+    pub dest_port: Vec<u8>,
+    pub dest_channel: H256,
+    pub acknowledgement: Vec<u8>,
+    pub _runtime: PhantomData<T>,
+}
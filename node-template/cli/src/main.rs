@@ -1,118 +1,262 @@
-use clap::{App, Arg, ArgMatches, SubCommand};
-use lazy_static::lazy_static;
-// use rand::RngCore;
 use calls::{
+    ibc::{self, RecvPacketEvent, SendPacketEvent},
     template::{
         TestBindPortCallExt, TestChanOpenInitCallExt, TestConnOpenInitCallExt,
         TestCreateClientCallExt, TestReleasePortCallExt, TestSendPacketCallExt,
     },
     NodeRuntime as Runtime,
 };
-use sp_core::{storage::StorageKey, Blake2Hasher, Hasher, H256};
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind, SubCommand};
+use codec::{Decode, Encode};
+use rand::RngCore;
+use relayer::config::{parse_h256, ChainConfig};
+use relayer::connection::{self, FINALITY_TIMEOUT};
+use serde::Serialize;
+use serde_derive::Deserialize;
+use serde_json::{json, Map, Value};
+use sp_core::{sr25519, storage::StorageKey, Pair, H256};
 use sp_finality_grandpa::{AuthorityList, VersionedAuthorityList, GRANDPA_AUTHORITIES_KEY};
 use sp_keyring::AccountKeyring;
 use std::collections::HashMap;
 use std::error::Error;
-use substrate_subxt::{BlockNumber, ClientBuilder, PairSigner};
-
-lazy_static! {
-    static ref ENDPOINTS: HashMap<&'static str, &'static str> = {
-        let mut m = HashMap::new();
-        m.insert("appia", "ws://127.0.0.1:9944");
-        m.insert("flaminia", "ws://127.0.0.1:8844");
-        m
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::process;
+use substrate_subxt::{
+    BlockNumber, ClientBuilder, Error as SubxtError, Event, ExtrinsicSuccess, ModuleError,
+    PairSigner, RawEvent, RuntimeError, Store,
+};
+
+/// The part of the relayer configuration the CLI reads: the chains it knows by name.
+#[derive(Debug, Default, Deserialize)]
+struct Config {
+    #[serde(default)]
+    chains: HashMap<String, ChainConfig>,
+}
+
+/// A chain the CLI operates on.
+struct Target {
+    endpoint: String,
+    /// The client on the chain that tracks its counterparty, if configured.
+    client_identifier: Option<H256>,
+}
+
+/// Reads the configuration file, which may only be missing if it was not asked for explicitly.
+fn load_config(matches: &ArgMatches) -> Result<Config, Box<dyn Error>> {
+    let file_path = matches.value_of("config").unwrap();
+    let contents = match fs::read_to_string(file_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && matches.occurrences_of("config") == 0 => {
+            return Ok(Config::default())
+        }
+        Err(e) => return Err(format!("can not read {}: {}", file_path, e).into()),
     };
+    toml::from_str(&contents).map_err(|e| format!("can not parse {}: {}", file_path, e).into())
+}
+
+/// Looks `name` up in `config`, reaching it at `url` instead of its endpoint if set.
+///
+/// A chain missing from `config` can still be reached at `url`.
+fn resolve_chain(config: &Config, name: &str, url: Option<&str>) -> Result<Target, Box<dyn Error>> {
+    match (config.chains.get(name), url) {
+        (Some(chain), _) => Ok(Target {
+            endpoint: url.unwrap_or(&chain.endpoint).to_string(),
+            client_identifier: Some(parse_h256(&chain.client_identifier, "client identifier")?),
+        }),
+        (None, Some(url)) => Ok(Target {
+            endpoint: url.to_string(),
+            client_identifier: None,
+        }),
+        (None, None) => Err(format!("chain {} not configured, pass its --url", name).into()),
+    }
+}
+
+/// Returns the key given by `--suri`, or Bob's.
+///
+/// The relayer signs with the key of the config file and keeps its nonce locally, so extrinsics
+/// signed with that key too would race it.
+fn signer_arg(matches: &ArgMatches) -> Result<sr25519::Pair, Box<dyn Error>> {
+    match matches.value_of("suri") {
+        Some(suri) => sr25519::Pair::from_string(suri, None)
+            .map_err(|e| format!("invalid secret uri: {:?}", e).into()),
+        None => Ok(AccountKeyring::Bob.pair()),
+    }
+}
+
+/// Returns a random identifier, unique for all practical purposes.
+fn fresh_identifier() -> H256 {
+    let mut data = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut data);
+    H256::from_slice(&data)
+}
+
+/// Returns the identifier given as `arg`, or `default` if there is none.
+fn identifier_arg(
+    matches: &ArgMatches,
+    arg: &str,
+    default: Option<H256>,
+) -> Result<H256, Box<dyn Error>> {
+    match matches.value_of(arg) {
+        Some(identifier) => parse_h256(identifier, "identifier"),
+        None => default.ok_or_else(|| format!("{} is required", arg).into()),
+    }
+}
+
+/// What a command did, printed as it goes, or at the end as a single JSON object with
+/// `--output json`.
+struct Report {
+    json: bool,
+    fields: Map<String, Value>,
+}
+
+impl Report {
+    fn new(json: bool) -> Self {
+        Self {
+            json,
+            fields: Map::new(),
+        }
+    }
+
+    /// Records `values` as the list `name`, printed one per line unless the output is JSON.
+    fn list(&mut self, name: &str, values: Vec<Value>) {
+        if self.json {
+            self.fields.insert(name.to_string(), Value::Array(values));
+        } else {
+            for value in values {
+                println!("{}: {}", name, value);
+            }
+        }
+    }
+
+    fn field<V: Debug + Serialize>(&mut self, name: &str, value: V) {
+        if self.json {
+            self.fields.insert(name.to_string(), json!(value));
+        } else {
+            println!("{}: {:?}", name, value);
+        }
+    }
+
+    /// Like `field`, for values only known by their `Debug` output.
+    fn debug_field<V: Debug>(&mut self, name: &str, value: V) {
+        if self.json {
+            self.fields
+                .insert(name.to_string(), json!(format!("{:?}", value)));
+        } else {
+            println!("{}: {:?}", name, value);
+        }
+    }
+
+    /// Prints the outcome of the command, returning whether it succeeded.
+    fn finish(mut self, result: Result<(), Box<dyn Error>>) -> bool {
+        if self.json {
+            self.fields.insert("ok".to_string(), json!(result.is_ok()));
+            if let Err(e) = &result {
+                self.fields
+                    .insert("error".to_string(), json!(e.to_string()));
+            }
+            println!("{}", Value::Object(self.fields));
+        } else if let Err(e) = &result {
+            eprintln!("error: {}", e);
+        }
+        result.is_ok()
+    }
 }
 
-fn execute(matches: ArgMatches) {
+fn execute(matches: &ArgMatches, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    let config = load_config(matches)?;
     let chain = matches.value_of("CHAIN").unwrap();
-    let addr = ENDPOINTS.get(chain).unwrap();
+    let target = resolve_chain(&config, chain, matches.value_of("url"))?;
+    let signer = signer_arg(matches)?;
+    let addr = &target.endpoint;
+    let finalized = matches.is_present("finalized");
     match matches.subcommand() {
         ("create-client", Some(matches)) => {
             let chain_name = matches
                 .value_of("chain-name")
                 .expect("The name of chain is required; qed");
-            let identifier = Blake2Hasher::hash(chain.as_bytes());
-            println!("identifier: {:?}", identifier);
+            // The relayer looks the client up under the configured identifier.
+            let default = target.client_identifier.unwrap_or_else(fresh_identifier);
+            let identifier = identifier_arg(matches, "identifier", Some(default))?;
+            report.field("identifier", identifier);
 
-            let counterparty_addr = ENDPOINTS.get(chain_name).unwrap();
-            let result =
-                async_std::task::block_on(create_client(&addr, &counterparty_addr, identifier));
-            println!("create_client: {:?}", result);
+            let counterparty =
+                resolve_chain(&config, chain_name, matches.value_of("counterparty-url"))?;
+            let success = async_std::task::block_on(create_client(
+                &addr,
+                &signer,
+                &counterparty.endpoint,
+                identifier,
+                report,
+            ))?;
+            async_std::task::block_on(report_inclusion(&addr, success, finalized, report))?;
         }
         ("conn-open-init", Some(matches)) => {
-            if chain != "appia" {
-                println!("CHAIN can only be appia in this demo");
-                return;
-            }
-            let client_identifier = matches
-                .value_of("client-identifier")
-                .expect("The identifier of chain is required; qed");
-            let client_identifier = hex::decode(client_identifier).unwrap();
-            let client_identifier = H256::from_slice(&client_identifier);
-
-            let counterparty_client_identifier = matches
-                .value_of("counterparty-client-identifier")
-                .expect("The identifier of counterparty chain is required; qed");
-            let counterparty_client_identifier =
-                hex::decode(counterparty_client_identifier).unwrap();
-            let counterparty_client_identifier = H256::from_slice(&counterparty_client_identifier);
-
-            // let mut data = [0u8; 32];
-            // rand::thread_rng().fill_bytes(&mut data);
-            // let identifier = H256::from_slice(&data);
-            // rand::thread_rng().fill_bytes(&mut data);
-            // let desired_counterparty_connection_identifier = H256::from_slice(&data);
-
-            let identifier = Blake2Hasher::hash(b"appia-connection");
-            println!("identifier: {:?}", identifier);
+            let client_identifier =
+                identifier_arg(matches, "client-identifier", target.client_identifier)?;
+            let counterparty_client_identifier = match matches.value_of("counterparty") {
+                Some(counterparty) => {
+                    let counterparty = config
+                        .chains
+                        .get(counterparty)
+                        .ok_or_else(|| format!("chain {} not configured", counterparty))?;
+                    Some(parse_h256(
+                        &counterparty.client_identifier,
+                        "client identifier",
+                    )?)
+                }
+                None => None,
+            };
+            let counterparty_client_identifier = identifier_arg(
+                matches,
+                "counterparty-client-identifier",
+                counterparty_client_identifier,
+            )?;
+
+            // The relayer looks the client up under the configured identifier.
+            let default = target.client_identifier.unwrap_or_else(fresh_identifier);
+            let identifier = identifier_arg(matches, "identifier", Some(default))?;
+            report.field("identifier", identifier);
             let desired_counterparty_connection_identifier =
-                Blake2Hasher::hash(b"flaminia-connection");
-            println!(
-                "desired_counterparty_connection_identifier: {:?}",
-                desired_counterparty_connection_identifier
+                identifier_arg(matches, "counterparty-identifier", Some(fresh_identifier()))?;
+            report.field(
+                "desired_counterparty_connection_identifier",
+                desired_counterparty_connection_identifier,
             );
 
-            let result = async_std::task::block_on(conn_open_init(
+            let success = async_std::task::block_on(conn_open_init(
                 &addr,
+                &signer,
                 identifier,
                 desired_counterparty_connection_identifier,
                 client_identifier,
                 counterparty_client_identifier,
-            ));
-            println!("conn_open_init: {:?}", result);
+            ))?;
+            async_std::task::block_on(report_inclusion(&addr, success, finalized, report))?;
         }
         ("bind-port", Some(matches)) => {
             let identifier = matches
                 .value_of("identifier")
                 .expect("The identifier of port is required; qed");
+            report.field("identifier", identifier);
             let identifier = identifier.as_bytes().to_vec();
-            println!("identifier: {:?}", identifier);
 
-            let result = async_std::task::block_on(bind_port(&addr, identifier));
-            println!("bind_port: {:?}", result);
+            let success = async_std::task::block_on(bind_port(&addr, &signer, identifier))?;
+            async_std::task::block_on(report_inclusion(&addr, success, finalized, report))?;
         }
         ("release-port", Some(matches)) => {
             let identifier = matches
                 .value_of("identifier")
                 .expect("The identifier of port is required; qed");
+            report.field("identifier", identifier);
             let identifier = identifier.as_bytes().to_vec();
-            println!("identifier: {:?}", identifier);
 
-            let result = async_std::task::block_on(release_port(&addr, identifier));
-            println!("release_port: {:?}", result);
+            let success = async_std::task::block_on(release_port(&addr, &signer, identifier))?;
+            async_std::task::block_on(report_inclusion(&addr, success, finalized, report))?;
         }
         ("chan-open-init", Some(matches)) => {
-            if chain != "appia" {
-                println!("CHAIN can only be appia in this demo");
-                return;
-            }
             let unordered = matches.is_present("unordered");
-            let connection_identifier = matches
-                .value_of("connection-identifier")
-                .expect("The identifier of connection is required; qed");
-            let connection_identifier = hex::decode(connection_identifier).unwrap();
-            let connection_identifier = H256::from_slice(&connection_identifier);
+            let connection_identifier = identifier_arg(matches, "connection-identifier", None)?;
             let connection_hops = vec![connection_identifier];
             let port_identifier = matches
                 .value_of("port-identifier")
@@ -123,69 +267,63 @@ fn execute(matches: ArgMatches) {
                 .expect("The identifier of counterparty port is required; qed");
             let counterparty_port_identifier = counterparty_port_identifier.as_bytes().to_vec();
 
-            // let mut data = [0u8; 32];
-            // rand::thread_rng().fill_bytes(&mut data);
-            // let channel_identifier = H256::from_slice(&data);
-            // rand::thread_rng().fill_bytes(&mut data);
-            // let desired_counterparty_channel_identifier = H256::from_slice(&data);
-
-            let channel_identifier = Blake2Hasher::hash(b"appia-channel");
-            println!("channel_identifier: {:?}", channel_identifier);
-            let desired_counterparty_channel_identifier = Blake2Hasher::hash(b"flaminia-channel");
-            println!(
-                "desired_counterparty_channel_identifier: {:?}",
-                desired_counterparty_channel_identifier
+            let channel_identifier =
+                identifier_arg(matches, "channel-identifier", Some(fresh_identifier()))?;
+            report.field("channel_identifier", channel_identifier);
+            let desired_counterparty_channel_identifier = identifier_arg(
+                matches,
+                "counterparty-channel-identifier",
+                Some(fresh_identifier()),
+            )?;
+            report.field(
+                "desired_counterparty_channel_identifier",
+                desired_counterparty_channel_identifier,
             );
 
-            let result = async_std::task::block_on(chan_open_init(
+            let success = async_std::task::block_on(chan_open_init(
                 &addr,
+                &signer,
                 unordered,
                 connection_hops,
                 port_identifier,
                 channel_identifier,
                 counterparty_port_identifier,
                 desired_counterparty_channel_identifier,
-            ));
-            println!("chan_open_init: {:?}", result);
+            ))?;
+            async_std::task::block_on(report_inclusion(&addr, success, finalized, report))?;
         }
         ("send-packet", Some(matches)) => {
-            if chain != "appia" {
-                println!("CHAIN can only be appia in this demo");
-                return;
-            }
             let sequence = matches
                 .value_of("sequence")
                 .expect("The sequence of packet is required; qed");
-            let sequence: u64 = sequence.parse().unwrap();
+            let sequence: u64 = sequence
+                .parse()
+                .map_err(|e| format!("invalid sequence {}: {}", sequence, e))?;
             let timeout_height = matches
                 .value_of("timeout-height")
                 .expect("The timeout-height of packet is required; qed");
-            let timeout_height: u32 = timeout_height.parse().unwrap();
+            let timeout_height: u32 = timeout_height
+                .parse()
+                .map_err(|e| format!("invalid timeout-height {}: {}", timeout_height, e))?;
             let source_port = matches
                 .value_of("source-port")
                 .expect("The source-port of packet is required; qed");
             let source_port = source_port.as_bytes().to_vec();
-            let source_channel = matches
-                .value_of("source-channel")
-                .expect("The source-channel of packet is required; qed");
-            let source_channel = hex::decode(source_channel).unwrap();
-            let source_channel = H256::from_slice(&source_channel);
+            let source_channel = identifier_arg(matches, "source-channel", None)?;
             let dest_port = matches
                 .value_of("dest-port")
                 .expect("The dest-port of packet is required; qed");
             let dest_port = dest_port.as_bytes().to_vec();
-            let dest_channel = matches
-                .value_of("dest-channel")
-                .expect("The dest-channel of packet is required; qed");
-            let dest_channel = hex::decode(dest_channel).unwrap();
-            let dest_channel = H256::from_slice(&dest_channel);
+            let dest_channel = identifier_arg(matches, "dest-channel", None)?;
             let data = matches
                 .value_of("data")
                 .expect("The data of packet is required; qed");
-            let data: Vec<u8> = hex::decode(data).expect("Invalid message");
+            let data: Vec<u8> =
+                hex::decode(data).map_err(|e| format!("invalid data {}: {}", data, e))?;
 
-            let result = async_std::task::block_on(send_packet(
+            let success = async_std::task::block_on(send_packet(
                 &addr,
+                &signer,
                 sequence,
                 timeout_height,
                 source_port,
@@ -193,22 +331,176 @@ fn execute(matches: ArgMatches) {
                 dest_port,
                 dest_channel,
                 data,
-            ));
-            println!("send_packet: {:?}", result);
+            ))?;
+            async_std::task::block_on(report_inclusion(&addr, success, finalized, report))?;
         }
-        _ => print_usage(&matches),
+        ("query", Some(matches)) => return execute_query(&addr, matches, report),
+        _ => return Err(usage_error(matches)),
+    }
+    Ok(())
+}
+
+fn execute_query(
+    addr: &str,
+    matches: &ArgMatches,
+    report: &mut Report,
+) -> Result<(), Box<dyn Error>> {
+    let (item, matches) = match matches.subcommand() {
+        (item, Some(matches)) => (item, matches),
+        _ => return Err(usage_error(matches)),
+    };
+    let at = match matches.value_of("at") {
+        Some(hash) => Some(parse_h256(hash, "block hash")?),
+        None => None,
+    };
+    let prove = matches.is_present("prove");
+    let port = || matches.value_of("port").unwrap().as_bytes().to_vec();
+    let sequence = || matches.value_of("sequence").unwrap().parse::<u64>();
+    match item {
+        "client" => async_std::task::block_on(query(
+            addr,
+            ibc::ClientsStore::<Runtime> {
+                key: identifier_arg(matches, "identifier", None)?,
+                _runtime: Default::default(),
+            },
+            at,
+            prove,
+            report,
+        )),
+        "consensus-state" => async_std::task::block_on(query(
+            addr,
+            ibc::ConsensusStatesStore::<Runtime> {
+                key: (
+                    identifier_arg(matches, "client-identifier", None)?,
+                    matches.value_of("height").unwrap().parse()?,
+                ),
+                _runtime: Default::default(),
+            },
+            at,
+            prove,
+            report,
+        )),
+        "connection" => async_std::task::block_on(query(
+            addr,
+            ibc::ConnectionsStore::<Runtime> {
+                key: identifier_arg(matches, "identifier", None)?,
+                _runtime: Default::default(),
+            },
+            at,
+            prove,
+            report,
+        )),
+        "channel" => async_std::task::block_on(query(
+            addr,
+            ibc::ChannelsStore::<Runtime> {
+                key: (port(), identifier_arg(matches, "identifier", None)?),
+                _runtime: Default::default(),
+            },
+            at,
+            prove,
+            report,
+        )),
+        "packet-commitment" => async_std::task::block_on(query(
+            addr,
+            ibc::PacketsStore::<Runtime> {
+                key: (
+                    port(),
+                    identifier_arg(matches, "channel", None)?,
+                    sequence()?,
+                ),
+                _runtime: Default::default(),
+            },
+            at,
+            prove,
+            report,
+        )),
+        "ack" => async_std::task::block_on(query(
+            addr,
+            ibc::AcknowledgementsStore::<Runtime> {
+                key: (
+                    port(),
+                    identifier_arg(matches, "channel", None)?,
+                    sequence()?,
+                ),
+                _runtime: Default::default(),
+            },
+            at,
+            prove,
+            report,
+        )),
+        _ => unreachable!("unknown query {}", item),
     }
 }
 
-fn print_usage(matches: &ArgMatches) {
-    println!("{}", matches.usage());
+fn usage_error(matches: &ArgMatches) -> Box<dyn Error> {
+    format!("no command given\n{}", matches.usage()).into()
+}
+
+/// A subcommand of `query`, reading the state at the best block or the one given by `--at`.
+fn query_subcommand<'a, 'b>(name: &'a str, about: &'a str, usage: &'a str) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .about(about)
+        .args_from_usage(usage)
+        .args_from_usage(
+            "
+--at=[HASH] 'Sets the block whose state is read, the best block by default'
+--prove 'Also prints the storage proof of the value'
+",
+        )
+}
+
+/// The `--output` argument, global so that it may follow the subcommand.
+fn output_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("output")
+        .long("output")
+        .value_name("FORMAT")
+        .help("Sets how the outcome is printed, json printing a single object")
+        .takes_value(true)
+        .possible_values(&["text", "json"])
+        .default_value("text")
+        .global(true)
+}
+
+/// Whether the arguments ask for `--output json`, parsing that argument alone and taking the
+/// others as they come, so that errors parsing all of them are printed as JSON too.
+fn json_output() -> bool {
+    App::new("cli")
+        .setting(AppSettings::AllowLeadingHyphen)
+        .setting(AppSettings::DisableHelpFlags)
+        .setting(AppSettings::DisableVersion)
+        .arg(output_arg())
+        .arg(Arg::with_name("ARGS").multiple(true))
+        .get_matches_safe()
+        .map_or(false, |matches| matches.value_of("output") == Some("json"))
 }
 
 fn main() {
-    let matches = App::new("cli")
+    let json = json_output();
+    let mut app = App::new("cli")
         .author("Cdot Network <ys@cdot.network>")
         .about("cli is a tool for testing IBC protocol")
         .version(env!("CARGO_PKG_VERSION"))
+        .arg(Arg::with_name("config")
+             .short("c")
+             .long("config")
+             .value_name("FILE")
+             .help("Sets the config file naming the chains, shared with the relayer")
+             .takes_value(true)
+             .default_value("relayer/config.toml"))
+        .arg(Arg::with_name("url")
+             .long("url")
+             .value_name("URL")
+             .help("Sets the endpoint of CHAIN, overriding the config file")
+             .takes_value(true))
+        .arg(Arg::with_name("suri")
+             .long("suri")
+             .value_name("SECRET_URI")
+             .help("Sets the key signing the extrinsics, //Bob by default, not the one of the relayer so as not to race its nonce")
+             .takes_value(true))
+        .arg(output_arg())
+        .arg(Arg::with_name("finalized")
+             .long("finalized")
+             .help("Waits for the block including a submitted extrinsic to be finalized"))
         .arg(Arg::with_name("CHAIN")
              .help("Sets the chain to be operated")
              .required(true))
@@ -216,6 +508,8 @@ fn main() {
             .about("Create a new client")
             .args_from_usage(
                 "
+--counterparty-url=[URL] 'Sets the endpoint of the counterparty chain, overriding the config file'
+--identifier=[IDENTIFIER] 'Sets the identifier of the client, the configured one by default or a fresh one for a chain missing from the config file'
 <chain-name> 'The name of counterparty demo chain'
 ",
             )])
@@ -223,8 +517,11 @@ fn main() {
             .about("Open a new connection")
             .args_from_usage(
                 "
-<client-identifier> 'The client identifier of demo chain'
-<counterparty-client-identifier> 'The client identifier of counterparty demo chain'
+--counterparty=[CHAIN] 'Sets the counterparty chain, whose configured client identifier is used'
+--identifier=[IDENTIFIER] 'Sets the identifier of the connection, a fresh one by default'
+--counterparty-identifier=[IDENTIFIER] 'Sets the desired identifier of the connection on the counterparty chain, a fresh one by default'
+[client-identifier] 'The client identifier of demo chain, the configured one by default'
+[counterparty-client-identifier] 'The client identifier of counterparty demo chain'
 ",
             )])
         .subcommands(vec![SubCommand::with_name("bind-port")
@@ -246,6 +543,8 @@ fn main() {
             .args_from_usage(
                 "
 --unordered 'Channel is unordered'
+--channel-identifier=[IDENTIFIER] 'Sets the identifier of the channel, a fresh one by default'
+--counterparty-channel-identifier=[IDENTIFIER] 'Sets the desired identifier of the channel on the counterparty chain, a fresh one by default'
 <connection-identifier> 'The connection identifier of demo chain'
 <port-identifier> 'The identifier of port'
 <counterparty-port-identifier> 'The identifier of port on counterparty chain'
@@ -264,16 +563,215 @@ fn main() {
 <data> 'The data is an opaque value which can be defined by the application logic of the associated modules'
 ",
             )])
-        .get_matches();
-    execute(matches);
+        .subcommand(
+            SubCommand::with_name("query")
+                .about("Read the IBC state of the chain")
+                .subcommand(query_subcommand(
+                    "client",
+                    "Query a client",
+                    "<identifier> 'The identifier of the client'",
+                ))
+                .subcommand(query_subcommand(
+                    "consensus-state",
+                    "Query a consensus state of a client",
+                    "
+<client-identifier> 'The identifier of the client'
+<height> 'The height of the consensus state'
+",
+                ))
+                .subcommand(query_subcommand(
+                    "connection",
+                    "Query a connection",
+                    "<identifier> 'The identifier of the connection'",
+                ))
+                .subcommand(query_subcommand(
+                    "channel",
+                    "Query a channel",
+                    "
+<port> 'The identifier of the port of the channel'
+<identifier> 'The identifier of the channel'
+",
+                ))
+                .subcommand(query_subcommand(
+                    "packet-commitment",
+                    "Query the commitment of a sent packet",
+                    "
+<port> 'The identifier of the port of the channel'
+<channel> 'The identifier of the channel'
+<sequence> 'The sequence of the packet'
+",
+                ))
+                .subcommand(query_subcommand(
+                    "ack",
+                    "Query the acknowledgement of a received packet",
+                    "
+<port> 'The identifier of the port of the channel'
+<channel> 'The identifier of the channel'
+<sequence> 'The sequence of the packet'
+",
+                )),
+        );
+    if json {
+        app = app.global_setting(AppSettings::ColorNever);
+    }
+    let mut report = Report::new(json);
+    let matches = match app.get_matches_safe() {
+        Ok(matches) => matches,
+        Err(e)
+            if !json
+                || e.kind == ErrorKind::HelpDisplayed
+                || e.kind == ErrorKind::VersionDisplayed =>
+        {
+            e.exit()
+        }
+        Err(e) => {
+            let message = e.message.trim_start_matches("error: ").to_string();
+            report.finish(Err(message.into()));
+            process::exit(1);
+        }
+    };
+    let result = execute(&matches, &mut report);
+    if !report.finish(result) {
+        process::exit(1);
+    }
+}
+
+/// Reports the value `store` holds at the block `at`, the best block by default, and its storage
+/// proof if `prove` is set.
+async fn query<S>(
+    addr: &str,
+    store: S,
+    at: Option<H256>,
+    prove: bool,
+    report: &mut Report,
+) -> Result<(), Box<dyn Error>>
+where
+    S: Store<Runtime>,
+    S::Returns: Debug + Encode,
+{
+    let client = ClientBuilder::<Runtime>::new()
+        .set_url(addr)
+        .build()
+        .await?;
+    // Resolved once, so that the value and its proof are read from the same block.
+    let at = match at {
+        Some(at) => at,
+        None => client
+            .block_hash(None)
+            .await?
+            .ok_or("best block not found")?,
+    };
+    report.field("at", at);
+    let value = client
+        .fetch(&store, Some(at))
+        .await?
+        .ok_or_else(|| format!("{} not found", S::FIELD))?;
+    report.debug_field(S::FIELD, &value);
+    report.field("encoded", format!("0x{}", hex::encode(value.encode())));
+    if prove {
+        let key = store.key(client.metadata())?;
+        let proof = client.read_proof(vec![key], Some(at)).await?;
+        let proof: Vec<_> = proof
+            .proof
+            .into_iter()
+            .map(|node| format!("0x{}", hex::encode(node.0)))
+            .collect();
+        report.field("proof", proof);
+    }
+    Ok(())
+}
+
+/// Describes why an extrinsic failed, naming the pallet error if it was included but failed.
+fn extrinsic_error(e: SubxtError) -> Box<dyn Error> {
+    match e {
+        SubxtError::Runtime(RuntimeError::Module(ModuleError { module, error })) => {
+            format!("extrinsic failed: {}::{}", module, error).into()
+        }
+        SubxtError::Runtime(e) => format!("extrinsic failed: {}", e).into(),
+        e => e.into(),
+    }
+}
+
+/// Reports the block that included an extrinsic and the IBC events it emitted, once the block is
+/// finalized if `finalized` is set.
+async fn report_inclusion(
+    addr: &str,
+    success: ExtrinsicSuccess<Runtime>,
+    finalized: bool,
+    report: &mut Report,
+) -> Result<(), Box<dyn Error>> {
+    report.field("extrinsic", success.extrinsic);
+    report.field("block", success.block);
+    let events = success
+        .events
+        .iter()
+        .filter(|event| event.module == SendPacketEvent::<Runtime>::MODULE)
+        .map(ibc_event)
+        .collect();
+    report.list("events", events);
+    if finalized {
+        wait_for_finality(addr, success.block).await?;
+        report.field("finalized", true);
+    }
+    Ok(())
+}
+
+/// Describes an event of the IBC pallet, decoded if it is one the CLI knows.
+fn ibc_event(event: &RawEvent) -> Value {
+    let decoded = if event.variant == SendPacketEvent::<Runtime>::EVENT {
+        SendPacketEvent::<Runtime>::decode(&mut &event.data[..])
+            .ok()
+            .map(|event| format!("{:?}", event))
+    } else if event.variant == RecvPacketEvent::<Runtime>::EVENT {
+        RecvPacketEvent::<Runtime>::decode(&mut &event.data[..])
+            .ok()
+            .map(|event| format!("{:?}", event))
+    } else {
+        None
+    };
+    json!({
+        "variant": event.variant,
+        "data": format!("0x{}", hex::encode(&event.data)),
+        "decoded": decoded,
+    })
+}
+
+/// Waits until `block` is finalized, failing if another block is finalized at its height.
+async fn wait_for_finality(addr: &str, block: H256) -> Result<(), Box<dyn Error>> {
+    let client = ClientBuilder::<Runtime>::new()
+        .set_url(addr)
+        .build()
+        .await?;
+    // Subscribed first, so that the block cannot be finalized unnoticed.
+    let mut heads = client.subscribe_finalized_blocks().await?;
+    let number = client
+        .header(Some(block))
+        .await?
+        .ok_or_else(|| format!("block {:?} not found", block))?
+        .number;
+    while connection::finalized_head_height(&client).await? < number {
+        async_std::future::timeout(FINALITY_TIMEOUT, heads.next())
+            .await
+            .map_err(|_| format!("no block finalized within {:?}", FINALITY_TIMEOUT))?;
+    }
+    match client.block_hash(Some(BlockNumber::from(number))).await? {
+        Some(hash) if hash == block => Ok(()),
+        _ => Err(format!(
+            "block {:?} was retracted, another block is finalized at #{}",
+            block, number
+        )
+        .into()),
+    }
 }
 
 async fn create_client(
     addr: &str,
+    signer: &sr25519::Pair,
     counterparty_addr: &str,
     identifier: H256,
-) -> Result<(), Box<dyn Error>> {
-    let signer = PairSigner::new(AccountKeyring::Bob.pair());
+    report: &mut Report,
+) -> Result<ExtrinsicSuccess<Runtime>, Box<dyn Error>> {
+    let signer = PairSigner::new(signer.clone());
 
     let counterparty_client = ClientBuilder::<Runtime>::new()
         .set_url(counterparty_addr)
@@ -283,25 +781,25 @@ async fn create_client(
     let genesis_hash = counterparty_client
         .block_hash(Some(BlockNumber::from(0u32)))
         .await?;
-    println!("counterparty genesis_hash: {:?}", genesis_hash);
-    let genesis_header = counterparty_client.header(genesis_hash).await?.unwrap();
-    println!("counterparty genesis_header: {:?}", genesis_header);
+    report.field("counterparty_genesis_hash", genesis_hash);
+    let genesis_header = counterparty_client
+        .header(genesis_hash)
+        .await?
+        .ok_or("counterparty genesis header not found")?;
+    report.debug_field("counterparty_genesis_header", &genesis_header);
     let storage_key = StorageKey(GRANDPA_AUTHORITIES_KEY.to_vec());
     let genesis_authorities: AuthorityList = counterparty_client
         .fetch_unhashed::<VersionedAuthorityList>(storage_key, genesis_hash)
         .await?
         .map(|versioned| versioned.into())
-        .unwrap();
-    println!(
-        "counterparty genesis_authorities: {:?}",
-        genesis_authorities
-    );
+        .ok_or("counterparty genesis authorities not found")?;
+    report.debug_field("counterparty_genesis_authorities", &genesis_authorities);
     let client = ClientBuilder::<Runtime>::new()
         .set_url(addr)
         .build()
         .await?;
-    let _result = client
-        .test_create_client(
+    let success = client
+        .test_create_client_and_watch(
             &signer,
             identifier,
             0,
@@ -309,70 +807,88 @@ async fn create_client(
             genesis_authorities,
             genesis_header.state_root,
         )
-        .await?;
-    Ok(())
+        .await
+        .map_err(extrinsic_error)?;
+    Ok(success)
 }
 
 async fn conn_open_init(
     addr: &str,
+    signer: &sr25519::Pair,
     identifier: H256,
     desired_counterparty_connection_identifier: H256,
     client_identifier: H256,
     counterparty_client_identifier: H256,
-) -> Result<(), Box<dyn Error>> {
-    let signer = PairSigner::new(AccountKeyring::Bob.pair());
+) -> Result<ExtrinsicSuccess<Runtime>, Box<dyn Error>> {
+    let signer = PairSigner::new(signer.clone());
     let client = ClientBuilder::<Runtime>::new()
         .set_url(addr.clone())
         .build()
         .await?;
-    let _result = client
-        .test_conn_open_init(
+    let success = client
+        .test_conn_open_init_and_watch(
             &signer,
             identifier,
             desired_counterparty_connection_identifier,
             client_identifier,
             counterparty_client_identifier,
         )
-        .await?;
-    Ok(())
+        .await
+        .map_err(extrinsic_error)?;
+    Ok(success)
 }
 
-async fn bind_port(addr: &str, identifier: Vec<u8>) -> Result<(), Box<dyn Error>> {
-    let signer = PairSigner::new(AccountKeyring::Bob.pair());
+async fn bind_port(
+    addr: &str,
+    signer: &sr25519::Pair,
+    identifier: Vec<u8>,
+) -> Result<ExtrinsicSuccess<Runtime>, Box<dyn Error>> {
+    let signer = PairSigner::new(signer.clone());
     let client = ClientBuilder::<Runtime>::new()
         .set_url(addr.clone())
         .build()
         .await?;
-    let _result = client.test_bind_port(&signer, identifier).await?;
-    Ok(())
+    let success = client
+        .test_bind_port_and_watch(&signer, identifier)
+        .await
+        .map_err(extrinsic_error)?;
+    Ok(success)
 }
 
-async fn release_port(addr: &str, identifier: Vec<u8>) -> Result<(), Box<dyn Error>> {
-    let signer = PairSigner::new(AccountKeyring::Bob.pair());
+async fn release_port(
+    addr: &str,
+    signer: &sr25519::Pair,
+    identifier: Vec<u8>,
+) -> Result<ExtrinsicSuccess<Runtime>, Box<dyn Error>> {
+    let signer = PairSigner::new(signer.clone());
     let client = ClientBuilder::<Runtime>::new()
         .set_url(addr.clone())
         .build()
         .await?;
-    let _result = client.test_release_port(&signer, identifier).await?;
-    Ok(())
+    let success = client
+        .test_release_port_and_watch(&signer, identifier)
+        .await
+        .map_err(extrinsic_error)?;
+    Ok(success)
 }
 
 async fn chan_open_init(
     addr: &str,
+    signer: &sr25519::Pair,
     unordered: bool,
     connection_hops: Vec<H256>,
     port_identifier: Vec<u8>,
     channel_identifier: H256,
     counterparty_port_identifier: Vec<u8>,
     counterparty_channel_identifier: H256,
-) -> Result<(), Box<dyn Error>> {
-    let signer = PairSigner::new(AccountKeyring::Bob.pair());
+) -> Result<ExtrinsicSuccess<Runtime>, Box<dyn Error>> {
+    let signer = PairSigner::new(signer.clone());
     let client = ClientBuilder::<Runtime>::new()
         .set_url(addr.clone())
         .build()
         .await?;
-    let _result = client
-        .test_chan_open_init(
+    let success = client
+        .test_chan_open_init_and_watch(
             &signer,
             unordered,
             connection_hops,
@@ -381,12 +897,14 @@ async fn chan_open_init(
             counterparty_port_identifier,
             counterparty_channel_identifier,
         )
-        .await?;
-    Ok(())
+        .await
+        .map_err(extrinsic_error)?;
+    Ok(success)
 }
 
 async fn send_packet(
     addr: &str,
+    signer: &sr25519::Pair,
     sequence: u64,
     timeout_height: u32,
     source_port: Vec<u8>,
@@ -394,14 +912,14 @@ async fn send_packet(
     dest_port: Vec<u8>,
     dest_channel: H256,
     data: Vec<u8>,
-) -> Result<(), Box<dyn Error>> {
-    let signer = PairSigner::new(AccountKeyring::Bob.pair());
+) -> Result<ExtrinsicSuccess<Runtime>, Box<dyn Error>> {
+    let signer = PairSigner::new(signer.clone());
     let client = ClientBuilder::<Runtime>::new()
         .set_url(addr.clone())
         .build()
         .await?;
-    let _result = client
-        .test_send_packet(
+    let success = client
+        .test_send_packet_and_watch(
             &signer,
             sequence,
             timeout_height,
@@ -411,6 +929,7 @@ async fn send_packet(
             dest_channel,
             data,
         )
-        .await?;
-    Ok(())
+        .await
+        .map_err(extrinsic_error)?;
+    Ok(success)
 }
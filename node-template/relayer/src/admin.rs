@@ -0,0 +1,247 @@
+//! A local JSON-RPC interface over HTTP to inspect and steer a running relayer.
+//!
+//! Requests are HTTP `POST`s of a JSON-RPC 2.0 call, whose `params` name the path either as
+//! `["appia:flaminia"]` or as `{"path": "appia:flaminia"}`. Methods:
+//!
+//! - `paths`: the paths with their heights and the lag of the client of their source chain,
+//! - `pause` and `resume`: stop and restart relaying on a path,
+//! - `update_client`: bring the client of the source chain of a path up to its finalized head,
+//! - `pending_packets`: the packets of a path neither received nor timed out, by channel.
+use crate::chain::Chain;
+use crate::client_update;
+use crate::relay::{PathControl, RelayPath};
+use async_std::io::BufReader;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+/// Requests with a larger body are refused.
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// A relay path as the admin interface sees it.
+#[derive(Clone)]
+pub struct AdminPath<C> {
+    pub path: RelayPath<C>,
+    pub control: Arc<PathControl>,
+}
+
+/// The admin interface over the paths of a relayer.
+#[derive(Clone)]
+pub struct Admin<C> {
+    paths: Arc<RwLock<Vec<AdminPath<C>>>>,
+}
+
+impl<C: Chain> Admin<C> {
+    pub fn new(paths: Vec<AdminPath<C>>) -> Self {
+        Self {
+            paths: Arc::new(RwLock::new(paths)),
+        }
+    }
+
+    /// Replaces the paths, once the relayer has reloaded its configuration.
+    pub fn set_paths(&self, paths: Vec<AdminPath<C>>) {
+        *self.paths.write().unwrap() = paths;
+    }
+
+    /// Serves the interface on `address` until the listener fails.
+    pub async fn serve(self, address: SocketAddr) {
+        let listener = match TcpListener::bind(address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(
+                    "failed to serve the admin interface on {}; error = {}",
+                    address, e
+                );
+                return;
+            }
+        };
+        info!("serving the admin interface on {}", address);
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    let admin = self.clone();
+                    async_std::task::spawn(async move {
+                        if let Err(e) = admin.handle(stream).await {
+                            warn!("admin request failed; error = {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("failed to accept an admin connection; error = {}", e),
+            }
+        }
+    }
+
+    /// Answers the single request of `stream`.
+    async fn handle(&self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(&stream);
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+                break;
+            }
+            let mut header = line.splitn(2, ':');
+            if let (Some(name), Some(value)) = (header.next(), header.next()) {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let response = if content_length > MAX_BODY_LEN {
+            error_response(Value::Null, -32600, "request too large".to_string())
+        } else {
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).await?;
+            match serde_json::from_slice::<Value>(&body) {
+                Ok(request) => self.respond(request).await,
+                Err(e) => error_response(Value::Null, -32700, e.to_string()),
+            }
+        };
+        let body = response.to_string();
+        let mut stream = &stream;
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await?;
+        stream.flush().await
+    }
+
+    async fn respond(&self, request: Value) -> Value {
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let method = match request.get("method").and_then(Value::as_str) {
+            Some(method) => method.to_string(),
+            None => return error_response(id, -32600, "method missing".to_string()),
+        };
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+        match self.call(&method, &params).await {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => error_response(id, -32000, e),
+        }
+    }
+
+    /// Calls `method` with `params`, returning its result or why it failed.
+    pub async fn call(&self, method: &str, params: &Value) -> Result<Value, String> {
+        match method {
+            "paths" => {
+                let admin_paths = self.paths.read().unwrap().clone();
+                let mut paths = vec![];
+                for admin_path in &admin_paths {
+                    paths.push(path_status(admin_path).await?);
+                }
+                Ok(Value::Array(paths))
+            }
+            "pause" | "resume" => {
+                let admin_path = self.path(params)?;
+                admin_path.control.set_paused(method == "pause");
+                info!(
+                    "[{}] {}d from the admin interface",
+                    admin_path.path.name(),
+                    method
+                );
+                Ok(json!({ "path": admin_path.path.name(), "paused": method == "pause" }))
+            }
+            "update_client" => {
+                let path = self.path(params)?.path;
+                let latest_height = path
+                    .to
+                    .client_state(path.to_client_identifier, None)
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .latest_height;
+                let height = path
+                    .from
+                    .finalized_height()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let datagrams = client_update::client_updates(
+                    &path.from,
+                    path.to_client_identifier,
+                    latest_height,
+                    height,
+                )
+                .await
+                .map_err(|e| e.to_string())?;
+                let updates = datagrams.len();
+                for datagram in datagrams {
+                    path.to.submit(&path.name(), datagram).await?;
+                }
+                Ok(json!({ "path": path.name(), "updates": updates }))
+            }
+            "pending_packets" => {
+                let path = self.path(params)?.path;
+                let channels = path
+                    .pending_packets
+                    .lock()
+                    .unwrap()
+                    .by_channel(path.from.name(), path.to.name());
+                let channels: Vec<_> = channels
+                    .into_iter()
+                    .map(|((port, channel), sequences)| {
+                        json!({
+                            "port": String::from_utf8_lossy(&port),
+                            "channel": format!("0x{}", hex::encode(channel)),
+                            "sequences": sequences,
+                        })
+                    })
+                    .collect();
+                Ok(Value::Array(channels))
+            }
+            _ => Err(format!("unknown method {}", method)),
+        }
+    }
+
+    /// Returns the path named by `params`.
+    fn path(&self, params: &Value) -> Result<AdminPath<C>, String> {
+        let name = params
+            .get(0)
+            .or_else(|| params.get("path"))
+            .and_then(Value::as_str)
+            .ok_or("path missing")?;
+        self.paths
+            .read()
+            .unwrap()
+            .iter()
+            .find(|admin_path| admin_path.path.name() == name)
+            .cloned()
+            .ok_or_else(|| format!("unknown path {}", name))
+    }
+}
+
+async fn path_status<C: Chain>(admin_path: &AdminPath<C>) -> Result<Value, String> {
+    let path = &admin_path.path;
+    let client_height = path
+        .to
+        .client_state(path.to_client_identifier, None)
+        .await
+        .map_err(|e| e.to_string())?
+        .latest_height;
+    let finalized_height = admin_path.control.target_height();
+    Ok(json!({
+        "path": path.name(),
+        "paused": admin_path.control.is_paused(),
+        "relayed_height": admin_path.control.last_height(),
+        "finalized_height": finalized_height,
+        "client_height": client_height,
+        "client_lag": finalized_height.saturating_sub(client_height),
+    }))
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
@@ -0,0 +1,155 @@
+//! The view of a chain the relayer needs, so that relaying does not depend on a live node.
+use async_trait::async_trait;
+use codec::Encode;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use pallet_ibc::{ChannelEnd, ClientState, ConnectionEnd, ConsensusState, Datagram};
+use sp_core::H256;
+use sp_finality_grandpa::AuthorityList;
+use sp_runtime::{generic, traits::BlakeTwo256, Justification};
+use sp_trie::StorageProof;
+use std::error::Error;
+
+pub type Header = generic::Header<u32, BlakeTwo256>;
+pub type IbcEvent = pallet_ibc::Event<node_runtime::Runtime>;
+/// Finalized headers of a chain, in order.
+pub type HeaderStream = BoxStream<'static, Header>;
+/// The IBC events of blocks as they are imported, finalized or not, with their header.
+pub type EventStream = BoxStream<'static, (Header, Vec<IbcEvent>)>;
+/// The outcome of a submitted datagram, known once it is included or given up on.
+pub type Submission = BoxFuture<'static, Result<(), String>>;
+
+/// A storage item of a chain whose value, or absence, is proven to the counterparty.
+#[derive(Clone, Debug, Encode, PartialEq)]
+pub enum StorageItem {
+    /// Consensus state of a client at a height.
+    ConsensusState(H256, u32),
+    Connection(H256),
+    /// Channel end of a port.
+    Channel(Vec<u8>, H256),
+    /// Commitment of the packet sent on a port and channel with a sequence.
+    Packet(Vec<u8>, H256, u64),
+    /// Acknowledgement of the packet received from a port and channel with a sequence.
+    Acknowledgement(Vec<u8>, H256, u64),
+    /// The GRANDPA authority set.
+    GrandpaAuthorities,
+}
+
+/// A chain the relayer reads from and submits datagrams to.
+///
+/// Queries taking a block hash read the state at that block, `None` meaning the best block.
+#[async_trait]
+pub trait Chain: Clone + Send + Sync + 'static {
+    /// The name of the chain in the configuration, logs and metrics.
+    fn name(&self) -> &str;
+
+    /// Subscribes to the finalized heads of the chain.
+    async fn finalized_heads(&mut self) -> HeaderStream;
+
+    /// Whether the chain still answers requests.
+    async fn is_alive(&self) -> bool;
+
+    /// Replaces a connection that is no longer alive.
+    async fn reconnect(&mut self);
+
+    async fn finalized_height(&self) -> Result<u32, Box<dyn Error>>;
+
+    async fn block_hash(&self, height: u32) -> Result<Option<H256>, Box<dyn Error>>;
+
+    /// Returns the header of the block at `height`, failing if there is none.
+    async fn header(&self, height: u32) -> Result<Header, Box<dyn Error>>;
+
+    /// Returns the GRANDPA justification of the block `hash`, if it has one.
+    async fn justification(&self, hash: H256) -> Result<Option<Justification>, Box<dyn Error>>;
+
+    async fn authorities(&self, hash: H256) -> Result<AuthorityList, Box<dyn Error>>;
+
+    async fn client_state(
+        &self,
+        identifier: H256,
+        hash: Option<H256>,
+    ) -> Result<ClientState, Box<dyn Error>>;
+
+    /// Returns the consensus state the client `identifier` holds for `height`, if any.
+    async fn consensus_state(
+        &self,
+        identifier: H256,
+        height: u32,
+        hash: Option<H256>,
+    ) -> Result<Option<ConsensusState>, Box<dyn Error>>;
+
+    async fn connection_end(
+        &self,
+        identifier: H256,
+        hash: Option<H256>,
+    ) -> Result<ConnectionEnd, Box<dyn Error>>;
+
+    async fn channel_end(
+        &self,
+        port: Vec<u8>,
+        channel: H256,
+        hash: Option<H256>,
+    ) -> Result<ChannelEnd, Box<dyn Error>>;
+
+    /// Returns the port, channel and sequence of every packet whose commitment is stored in the
+    /// block `hash`, in ascending order.
+    async fn packets(&self, hash: H256) -> Result<Vec<(Vec<u8>, H256, u64)>, Box<dyn Error>>;
+
+    /// Returns the sequences of the packets sent on `port` and `channel` whose commitment is
+    /// stored in the block `hash`, in ascending order.
+    async fn packet_commitments(
+        &self,
+        port: Vec<u8>,
+        channel: H256,
+        hash: H256,
+    ) -> Result<Vec<u64>, Box<dyn Error>> {
+        Ok(self
+            .packets(hash)
+            .await?
+            .into_iter()
+            .filter(|(p, c, _)| *p == port && *c == channel)
+            .map(|(_, _, sequence)| sequence)
+            .collect())
+    }
+
+    /// Returns the commitment of the packet sent on `port` and `channel` with `sequence`, if it
+    /// is still stored, that is neither acknowledged nor timed out.
+    async fn packet_commitment(
+        &self,
+        port: Vec<u8>,
+        channel: H256,
+        sequence: u64,
+        hash: H256,
+    ) -> Result<Option<H256>, Box<dyn Error>>;
+
+    /// Returns the acknowledgement of a received packet, if it was received.
+    async fn acknowledgement(
+        &self,
+        port: Vec<u8>,
+        channel: H256,
+        sequence: u64,
+        hash: H256,
+    ) -> Result<Option<H256>, Box<dyn Error>>;
+
+    /// Returns the key under which `item` is stored.
+    fn storage_key(&self, item: &StorageItem) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    /// Reads a proof of `item` in the state of the block `hash`.
+    async fn read_proof(
+        &self,
+        item: StorageItem,
+        hash: H256,
+    ) -> Result<StorageProof, Box<dyn Error>>;
+
+    /// Returns the events the IBC pallet deposited in the block `hash`.
+    async fn ibc_events(&self, hash: H256) -> Result<Vec<IbcEvent>, Box<dyn Error>>;
+
+    /// Subscribes to the IBC events of the blocks the chain imports, before they are finalized.
+    async fn unfinalized_ibc_events(&self) -> EventStream;
+
+    /// Queues `datagram`, relayed on `path`, for submission to the chain.
+    ///
+    /// Datagrams are submitted in the order they are queued, whatever the path. The returned
+    /// outcome fails if the datagram could not be included or was rejected.
+    fn submit(&self, path: &str, datagram: Datagram) -> Submission;
+}
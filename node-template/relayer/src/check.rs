@@ -0,0 +1,211 @@
+//! Validation of a configuration against the live chains.
+use crate::Config;
+use calls::{ibc, NodeRuntime as Runtime};
+use relayer::{config::parse_h256, connection::finalized_head_height, signer};
+use sp_core::{sr25519, Pair, H256};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::time::Duration;
+use substrate_subxt::{
+    system::{AccountStoreExt, System},
+    BlockNumber, Client, ClientBuilder,
+};
+
+/// How long to wait for a node before reporting it unreachable.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Report {
+    problems: usize,
+}
+
+impl Report {
+    fn ok<S: AsRef<str>>(&mut self, message: S) {
+        println!("  ok: {}", message.as_ref());
+    }
+
+    fn problem<S: AsRef<str>>(&mut self, message: S) {
+        self.problems += 1;
+        println!("  error: {}", message.as_ref());
+    }
+}
+
+/// Checks every chain and relay path of `config`, printing a report to stdout.
+///
+/// Returns whether no problem was found.
+pub async fn check(config: &Config) -> bool {
+    let mut report = Report::default();
+    let mut clients = HashMap::new();
+    let mut client_identifiers = HashMap::new();
+
+    let chains: BTreeMap<_, _> = config.chains.iter().collect();
+    for (name, chain) in chains {
+        println!("chain {} ({})", name, chain.endpoint);
+        let connect = ClientBuilder::<Runtime>::new()
+            .set_url(chain.endpoint.as_str())
+            .build();
+        let client = match async_std::future::timeout(CONNECT_TIMEOUT, connect).await {
+            Ok(Ok(client)) => client,
+            Ok(Err(e)) => {
+                report.problem(format!("can not connect: {}", e));
+                continue;
+            }
+            Err(_) => {
+                report.problem("can not connect: timed out");
+                continue;
+            }
+        };
+        match finalized_head_height(&client).await {
+            Ok(height) => report.ok(format!("connected, finalized height #{}", height)),
+            Err(e) => report.problem(format!("can not read finalized head: {}", e)),
+        }
+        match parse_h256(&chain.client_identifier, "client identifier") {
+            Ok(identifier) => {
+                client_identifiers.insert(name.as_str(), identifier);
+            }
+            Err(e) => report.problem(format!("client_identifier: {}", e)),
+        }
+        match signer::load_pair(&chain.signer) {
+            Ok(pair) => {
+                if let Err(e) = check_signer(&mut report, &client, pair).await {
+                    report.problem(format!("can not read signer account: {}", e));
+                }
+            }
+            Err(e) => report.problem(format!("can not load signer: {}", e)),
+        }
+        clients.insert(name.as_str(), client);
+    }
+
+    for path in &config.relay {
+        println!("path {} -> {}", path.from, path.to);
+        let mut usable = true;
+        for name in &[&path.from, &path.to] {
+            if !config.chains.contains_key(name.as_str()) {
+                report.problem(format!("chain {} is not configured", name));
+                usable = false;
+            } else if !clients.contains_key(name.as_str()) {
+                report.problem(format!("chain {} is unreachable", name));
+                usable = false;
+            }
+        }
+        let identifier = match client_identifiers.get(path.from.as_str()) {
+            Some(identifier) if usable => *identifier,
+            _ => continue,
+        };
+        if let Err(e) = check_client(
+            &mut report,
+            &path.from,
+            &clients[path.from.as_str()],
+            identifier,
+            &path.to,
+            &clients[path.to.as_str()],
+        )
+        .await
+        {
+            report.problem(format!("can not check client: {}", e));
+        }
+    }
+
+    if report.problems == 0 {
+        println!("no problems found");
+    } else {
+        println!("{} problem(s) found", report.problems);
+    }
+    report.problems == 0
+}
+
+async fn check_signer(
+    report: &mut Report,
+    client: &Client<Runtime>,
+    pair: sr25519::Pair,
+) -> Result<(), Box<dyn Error>> {
+    let account_id: <Runtime as System>::AccountId = pair.public().into();
+    let account = client.account(&account_id, None).await?;
+    if account.data.free == 0 {
+        report.problem(format!("signer {} has no free balance", account_id));
+    } else {
+        report.ok(format!(
+            "signer {}, free balance {}, nonce {}",
+            account_id, account.data.free, account.nonce
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that the client `identifier` exists on `chain_name` and tracks `counterparty_name`.
+async fn check_client(
+    report: &mut Report,
+    chain_name: &str,
+    client: &Client<Runtime>,
+    identifier: H256,
+    counterparty_name: &str,
+    counterparty_client: &Client<Runtime>,
+) -> Result<(), Box<dyn Error>> {
+    let clients = ibc::ClientsStore::<Runtime> {
+        key: identifier,
+        _runtime: Default::default(),
+    };
+    let client_state = match client.fetch(&clients, None).await? {
+        Some(client_state) => client_state,
+        None => {
+            report.problem(format!(
+                "client {:?} does not exist on {}",
+                identifier, chain_name
+            ));
+            return Ok(());
+        }
+    };
+    report.ok(format!(
+        "client {:?} on {} is at height #{}",
+        identifier, chain_name, client_state.latest_height
+    ));
+
+    // The client tracks the counterparty if the state root it holds for its latest height is
+    // the one of the counterparty block at that height.
+    let height = client_state.latest_height;
+    let consensus_states = ibc::ConsensusStatesStore::<Runtime> {
+        key: (identifier, height),
+        _runtime: Default::default(),
+    };
+    let consensus_state = client.fetch(&consensus_states, None).await?;
+    let counterparty_hash = counterparty_client
+        .block_hash(Some(BlockNumber::from(height)))
+        .await?;
+    let counterparty_header = match counterparty_hash {
+        Some(hash) => counterparty_client.header(Some(hash)).await?,
+        None => None,
+    };
+    match (consensus_state, counterparty_header) {
+        (None, _) => report.problem(format!(
+            "client {:?} has no consensus state at height #{}",
+            identifier, height
+        )),
+        (Some(_), None) => report.problem(format!(
+            "{} has no block #{}, the client tracks another chain",
+            counterparty_name, height
+        )),
+        (Some(consensus_state), Some(header)) => {
+            if consensus_state.commitment_root == header.state_root {
+                report.ok(format!(
+                    "client tracks {} (state root at #{} matches)",
+                    counterparty_name, height
+                ));
+            } else {
+                report.problem(format!(
+                    "client does not track {}: state root at #{} is {:?}, expected {:?}",
+                    counterparty_name, height, consensus_state.commitment_root, header.state_root
+                ));
+            }
+        }
+    }
+
+    let counterparty_height = finalized_head_height(counterparty_client).await?;
+    report.ok(format!(
+        "client lags {} block(s) behind {} (#{} < #{})",
+        counterparty_height.saturating_sub(height),
+        counterparty_name,
+        height,
+        counterparty_height
+    ));
+    Ok(())
+}
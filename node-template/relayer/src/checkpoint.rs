@@ -0,0 +1,76 @@
+//! Persistent record of the last finalized height fully relayed on each path.
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Checkpoints {
+    #[serde(default)]
+    paths: BTreeMap<String, u32>,
+}
+
+/// Checkpoints of all relay paths, backed by a TOML file.
+///
+/// The file is rewritten after every update, so a relayer killed at any point resumes from the
+/// last block whose datagrams were all handed over for submission.
+#[derive(Debug)]
+pub struct CheckpointStore {
+    path: PathBuf,
+    checkpoints: Checkpoints,
+    read_only: bool,
+}
+
+impl CheckpointStore {
+    /// Loads the checkpoints from `path`, starting empty if the file does not exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref().to_path_buf();
+        let checkpoints = match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Checkpoints::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path,
+            checkpoints,
+            read_only: false,
+        })
+    }
+
+    /// Like `open`, but updates are only kept in memory and never written back to `path`.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let mut store = Self::open(path)?;
+        store.read_only = true;
+        Ok(store)
+    }
+
+    /// Returns the last height relayed from `from` to `to`, if any.
+    pub fn get(&self, from: &str, to: &str) -> Option<u32> {
+        self.checkpoints.paths.get(&path_key(from, to)).copied()
+    }
+
+    /// Records `height` as the last height relayed from `from` to `to` and writes it to disk.
+    pub fn set(&mut self, from: &str, to: &str, height: u32) -> Result<(), Box<dyn Error>> {
+        self.checkpoints.paths.insert(path_key(from, to), height);
+        self.flush()
+    }
+
+    /// Writes the checkpoints to disk, unless the store is read-only.
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        if self.read_only {
+            return Ok(());
+        }
+        let contents = toml::to_string(&self.checkpoints)?;
+        // Write to a temporary file first so that a crash never leaves a truncated store behind.
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+fn path_key(from: &str, to: &str) -> String {
+    format!("{}:{}", from, to)
+}
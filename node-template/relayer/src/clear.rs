@@ -0,0 +1,297 @@
+//! One-shot relaying of the packets a path left outstanding, for instance while it was down.
+use crate::chain::{Chain, Header, StorageItem};
+use crate::client_update;
+use crate::proof::{read_verified_proof, Expected};
+use crate::relay::RelayPath;
+use log::info;
+use pallet_ibc::{Datagram, Packet, RawEvent};
+use sp_core::H256;
+use sp_runtime::traits::Header as _;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+
+/// How many blocks back from the finalized one are scanned for packet events, when no first
+/// block is given.
+pub const DEFAULT_SCAN_DEPTH: u32 = 14_400;
+
+/// What `clear_packets` relayed, by packet sequence.
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Packets sent but not received, now relayed to the destination.
+    pub received: Vec<u64>,
+    /// Packets received but not acknowledged, whose acknowledgement is now relayed to the source.
+    pub acknowledged: Vec<u64>,
+    /// Outstanding packets that could not be relayed, because their event was not found in the
+    /// scanned blocks, their proof did not check out or their datagram was rejected.
+    pub skipped: Vec<u64>,
+}
+
+/// Returns the port of the channel `channel` of the chain behind `chain`, looked up among the
+/// channels of its client `client_identifier`.
+pub async fn channel_port<C: Chain>(
+    chain: &C,
+    client_identifier: H256,
+    channel: H256,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let client_state = chain.client_state(client_identifier, None).await?;
+    client_state
+        .channels
+        .into_iter()
+        .find(|(_, identifier)| *identifier == channel)
+        .map(|(port, _)| port)
+        .ok_or_else(|| format!("channel {:?} not found on {}", channel, chain.name()).into())
+}
+
+/// Relays, in order of sequence, the packets sent on `port` and `channel` of the source chain of
+/// `path` that the destination has not received, and the acknowledgements of those it received
+/// that the source has not processed.
+///
+/// Outstanding packets are the commitments still stored on the source; their data comes from the
+/// events of the blocks from `from_height` on the source and from `counterparty_from_height` on
+/// the destination, each `DEFAULT_SCAN_DEPTH` blocks back from the finalized one by default.
+/// Datagrams are submitted directly, after the client updates they need.
+pub async fn clear_packets<C: Chain>(
+    path: &RelayPath<C>,
+    port: Vec<u8>,
+    channel: H256,
+    from_height: Option<u32>,
+    counterparty_from_height: Option<u32>,
+) -> Result<Summary, Box<dyn Error>> {
+    let mut summary = Summary::default();
+    let source_header = path
+        .from
+        .header(path.from.finalized_height().await?)
+        .await?;
+    let destination_header = path.to.header(path.to.finalized_height().await?).await?;
+    let sequences = path
+        .from
+        .packet_commitments(port.clone(), channel, source_header.hash())
+        .await?;
+    info!(
+        "[{}] {} packet commitment(s) outstanding on channel {:?}",
+        path.from.name(),
+        sequences.len(),
+        channel
+    );
+
+    let mut unreceived = BTreeSet::new();
+    let mut unacknowledged = BTreeSet::new();
+    for sequence in sequences {
+        let acknowledgement = path
+            .to
+            .acknowledgement(port.clone(), channel, sequence, destination_header.hash())
+            .await?;
+        match acknowledgement {
+            Some(_) => unacknowledged.insert(sequence),
+            None => unreceived.insert(sequence),
+        };
+    }
+
+    if !unreceived.is_empty() {
+        let proof_header = update_client(
+            &path.name(),
+            &path.from,
+            &path.to,
+            path.to_client_identifier,
+            &source_header,
+        )
+        .await?;
+        let packets = find_sent_packets(
+            &path.from,
+            &port,
+            channel,
+            &unreceived,
+            from_height.unwrap_or_else(|| source_header.number.saturating_sub(DEFAULT_SCAN_DEPTH)),
+            source_header.number,
+        )
+        .await?;
+        for sequence in unreceived {
+            let packet = match packets.get(&sequence) {
+                Some(packet) => packet.clone(),
+                None => {
+                    summary.skipped.push(sequence);
+                    continue;
+                }
+            };
+            let item = StorageItem::Packet(port.clone(), channel, sequence);
+            match read_verified_proof(&path.from, item, Expected::Exists, &proof_header).await? {
+                Some(proof) => {
+                    let datagram = Datagram::PacketRecv {
+                        packet,
+                        proof,
+                        proof_height: proof_header.number,
+                    };
+                    match path.to.submit(&path.name(), datagram).await {
+                        Ok(()) => summary.received.push(sequence),
+                        Err(_) => summary.skipped.push(sequence),
+                    }
+                }
+                None => summary.skipped.push(sequence),
+            }
+        }
+    }
+
+    if !unacknowledged.is_empty() {
+        let proof_header = update_client(
+            &path.name(),
+            &path.to,
+            &path.from,
+            path.from_client_identifier,
+            &destination_header,
+        )
+        .await?;
+        let receipts = find_received_packets(
+            &path.to,
+            &port,
+            channel,
+            &unacknowledged,
+            counterparty_from_height
+                .unwrap_or_else(|| destination_header.number.saturating_sub(DEFAULT_SCAN_DEPTH)),
+            destination_header.number,
+        )
+        .await?;
+        for sequence in unacknowledged {
+            let (packet, acknowledgement) = match receipts.get(&sequence) {
+                Some(receipt) => receipt.clone(),
+                None => {
+                    summary.skipped.push(sequence);
+                    continue;
+                }
+            };
+            let item = StorageItem::Acknowledgement(port.clone(), channel, sequence);
+            match read_verified_proof(&path.to, item, Expected::Exists, &proof_header).await? {
+                Some(proof) => {
+                    let datagram = Datagram::PacketAcknowledgement {
+                        packet,
+                        acknowledgement,
+                        proof,
+                        proof_height: proof_header.number,
+                    };
+                    match path.from.submit(&path.name(), datagram).await {
+                        Ok(()) => summary.acknowledged.push(sequence),
+                        Err(_) => summary.skipped.push(sequence),
+                    }
+                }
+                None => summary.skipped.push(sequence),
+            }
+        }
+    }
+    summary.skipped.sort_unstable();
+    Ok(summary)
+}
+
+/// Brings the client `client_identifier` on `counterparty`, which tracks `chain`, as close to
+/// `header` as justifications allow, and returns the header of `chain` it ends up at.
+async fn update_client<C: Chain>(
+    path_name: &str,
+    chain: &C,
+    counterparty: &C,
+    client_identifier: H256,
+    header: &Header,
+) -> Result<Header, Box<dyn Error>> {
+    let client_state = counterparty.client_state(client_identifier, None).await?;
+    let mut height = client_state.latest_height;
+    for datagram in
+        client_update::client_updates(chain, client_identifier, height, header.number).await?
+    {
+        if let Datagram::ClientUpdate { header: update, .. } = &datagram {
+            height = update.height;
+        }
+        counterparty.submit(path_name, datagram).await?;
+    }
+    chain.header(height).await
+}
+
+/// Returns the packets with one of `sequences` sent on `port` and `channel` in the blocks of
+/// `chain` from `from_height` to `to_height`.
+async fn find_sent_packets<C: Chain>(
+    chain: &C,
+    port: &[u8],
+    channel: H256,
+    sequences: &BTreeSet<u64>,
+    from_height: u32,
+    to_height: u32,
+) -> Result<BTreeMap<u64, Packet>, Box<dyn Error>> {
+    let mut packets = BTreeMap::new();
+    for height in from_height..=to_height {
+        if packets.len() == sequences.len() {
+            break;
+        }
+        let hash = chain.header(height).await?.hash();
+        for event in chain.ibc_events(hash).await? {
+            if let RawEvent::SendPacket(
+                sequence,
+                data,
+                timeout_height,
+                source_port,
+                source_channel,
+                dest_port,
+                dest_channel,
+            ) = event
+            {
+                if source_port == port && source_channel == channel && sequences.contains(&sequence)
+                {
+                    let packet = Packet {
+                        sequence,
+                        timeout_height,
+                        source_port,
+                        source_channel,
+                        dest_port,
+                        dest_channel,
+                        data,
+                    };
+                    packets.insert(sequence, packet);
+                }
+            }
+        }
+    }
+    Ok(packets)
+}
+
+/// Returns the packets with one of `sequences` sent on `port` and `channel` of the counterparty
+/// and received in the blocks of `chain` from `from_height` to `to_height`, with their
+/// acknowledgements.
+async fn find_received_packets<C: Chain>(
+    chain: &C,
+    port: &[u8],
+    channel: H256,
+    sequences: &BTreeSet<u64>,
+    from_height: u32,
+    to_height: u32,
+) -> Result<BTreeMap<u64, (Packet, Vec<u8>)>, Box<dyn Error>> {
+    let mut receipts = BTreeMap::new();
+    for height in from_height..=to_height {
+        if receipts.len() == sequences.len() {
+            break;
+        }
+        let hash = chain.header(height).await?.hash();
+        for event in chain.ibc_events(hash).await? {
+            if let RawEvent::RecvPacket(
+                sequence,
+                data,
+                timeout_height,
+                source_port,
+                source_channel,
+                dest_port,
+                dest_channel,
+                acknowledgement,
+            ) = event
+            {
+                if source_port == port && source_channel == channel && sequences.contains(&sequence)
+                {
+                    let packet = Packet {
+                        sequence,
+                        timeout_height,
+                        source_port,
+                        source_channel,
+                        dest_port,
+                        dest_channel,
+                        data,
+                    };
+                    receipts.insert(sequence, (packet, acknowledgement));
+                }
+            }
+        }
+    }
+    Ok(receipts)
+}
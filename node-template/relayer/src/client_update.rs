@@ -0,0 +1,138 @@
+//! Selection of the headers a counterparty client needs to catch up with a chain.
+use crate::chain::{Chain, StorageItem};
+use crate::proof::{read_verified_proof, Expected};
+use log::{debug, warn};
+use pallet_ibc::{Datagram, Header};
+use sp_core::H256;
+use sp_finality_grandpa::AuthorityList;
+use sp_runtime::traits::Header as _;
+use std::error::Error;
+
+/// Builds the client updates that bring a client of `chain`, currently at `latest_height`, up to
+/// `height`.
+///
+/// A GRANDPA client can verify any justified header signed by the authority set it knows, so it
+/// only needs the headers enacting an authority set change, each signed by the previous set,
+/// followed by the newest justified header.
+pub async fn client_updates<C: Chain>(
+    chain: &C,
+    identifier: H256,
+    latest_height: u32,
+    height: u32,
+) -> Result<Vec<Datagram>, Box<dyn Error>> {
+    if latest_height >= height {
+        return Ok(vec![]);
+    }
+    let mut datagrams = vec![];
+    let mut last_update_height = latest_height;
+    for change_height in authority_set_changes(chain, latest_height, height).await? {
+        match client_update(chain, identifier, change_height).await? {
+            Some(datagram) => {
+                datagrams.push(datagram);
+                last_update_height = change_height;
+            }
+            None => warn!(
+                "block #{} enacts an authority set change but has no justification",
+                change_height
+            ),
+        }
+    }
+    for newest_height in (last_update_height + 1..=height).rev() {
+        if let Some(datagram) = client_update(chain, identifier, newest_height).await? {
+            datagrams.push(datagram);
+            break;
+        }
+    }
+    debug!(
+        "{} client updates from #{} to #{}",
+        datagrams.len(),
+        latest_height,
+        height
+    );
+    Ok(datagrams)
+}
+
+/// Returns the heights in `(low, high]` at which the GRANDPA authority set changes, in
+/// ascending order.
+///
+/// Bisects on the authority list, so it costs a few storage reads per change instead of one per
+/// block. A set that changes and later changes back within the range is not detected.
+async fn authority_set_changes<C: Chain>(
+    chain: &C,
+    low: u32,
+    high: u32,
+) -> Result<Vec<u32>, Box<dyn Error>> {
+    let mut changes = vec![];
+    let mut ranges = vec![(
+        low,
+        authorities_at(chain, low).await?,
+        high,
+        authorities_at(chain, high).await?,
+    )];
+    while let Some((low, low_authorities, high, high_authorities)) = ranges.pop() {
+        if low_authorities == high_authorities {
+            continue;
+        }
+        if high == low + 1 {
+            changes.push(high);
+            continue;
+        }
+        let middle = low + (high - low) / 2;
+        let middle_authorities = authorities_at(chain, middle).await?;
+        // The lower half is pushed last, so that it is popped first.
+        ranges.push((middle, middle_authorities.clone(), high, high_authorities));
+        ranges.push((low, low_authorities, middle, middle_authorities));
+    }
+    Ok(changes)
+}
+
+async fn authorities_at<C: Chain>(chain: &C, height: u32) -> Result<AuthorityList, Box<dyn Error>> {
+    let hash = chain
+        .block_hash(height)
+        .await?
+        .ok_or_else(|| format!("block #{} not found", height))?;
+    chain.authorities(hash).await
+}
+
+/// Builds the update to the header at `height`, if that header is justified and the proof of its
+/// authority set checks out.
+async fn client_update<C: Chain>(
+    chain: &C,
+    identifier: H256,
+    height: u32,
+) -> Result<Option<Datagram>, Box<dyn Error>> {
+    let header = justified_header(chain, height).await?;
+    Ok(header.map(|header| Datagram::ClientUpdate { identifier, header }))
+}
+
+/// Returns the header at `height` as a client verifies it, if that header is justified and the
+/// proof of its authority set checks out.
+pub async fn justified_header<C: Chain>(
+    chain: &C,
+    height: u32,
+) -> Result<Option<Header>, Box<dyn Error>> {
+    let header = chain.header(height).await?;
+    let hash = header.hash();
+    let justification = match chain.justification(hash).await? {
+        Some(justification) => justification,
+        None => return Ok(None),
+    };
+    let authorities_proof = match read_verified_proof(
+        chain,
+        StorageItem::GrandpaAuthorities,
+        Expected::Exists,
+        &header,
+    )
+    .await?
+    {
+        Some(authorities_proof) => authorities_proof,
+        None => return Ok(None),
+    };
+    Ok(Some(Header {
+        height: header.number,
+        block_hash: hash,
+        commitment_root: header.state_root,
+        justification,
+        authorities_proof,
+    }))
+}
@@ -0,0 +1,24 @@
+//! The `[chains]` table of the relayer configuration, also read by the CLI.
+use crate::signer::SignerConfig;
+use serde_derive::Deserialize;
+use sp_core::H256;
+use std::error::Error;
+
+/// How to reach a chain and sign on it.
+#[derive(Debug, Deserialize)]
+pub struct ChainConfig {
+    pub endpoint: String,
+    /// The client on this chain that tracks its counterparty, in hex.
+    pub client_identifier: String,
+    /// The key used to sign the datagrams submitted to this chain.
+    pub signer: SignerConfig,
+}
+
+/// Parses `value`, 32 bytes written in hex with or without a `0x` prefix, naming it `what` in
+/// the error.
+pub fn parse_h256(value: &str, what: &str) -> Result<H256, Box<dyn Error>> {
+    match hex::decode(value.trim_start_matches("0x")) {
+        Ok(bytes) if bytes.len() == 32 => Ok(H256::from_slice(&bytes)),
+        _ => Err(format!("{} is not a {}, 32 bytes in hex", value, what).into()),
+    }
+}
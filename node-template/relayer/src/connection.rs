@@ -0,0 +1,82 @@
+//! Connections to chain nodes, rebuilt with backoff whenever a node goes away.
+use calls::NodeRuntime as Runtime;
+use jsonrpsee::client::Subscription;
+use log::{info, warn};
+use std::cmp;
+use std::error::Error;
+use std::time::Duration;
+use substrate_subxt::{system::System, Client, ClientBuilder};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How long a request may take before the connection is considered dead.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for a new finalized head before checking that the node is still there.
+pub const FINALITY_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub type Header = <Runtime as System>::Header;
+
+/// Connects to the node at `endpoint`, retrying until it is reachable.
+pub async fn connect(chain_name: &str, endpoint: &str) -> Client<Runtime> {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match ClientBuilder::<Runtime>::new()
+            .set_url(endpoint)
+            .build()
+            .await
+        {
+            Ok(client) => {
+                info!("[{}] connected to {}", chain_name, endpoint);
+                return client;
+            }
+            Err(e) => warn!(
+                "[{}] failed to connect to {}, retrying in {:?}; error = {}",
+                chain_name, endpoint, backoff, e
+            ),
+        }
+        async_std::task::sleep(backoff).await;
+        backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+}
+
+/// Connects to the node at `endpoint` and subscribes to its finalized heads, retrying until both
+/// succeed.
+pub async fn subscribe_finalized_heads(
+    chain_name: &str,
+    endpoint: &str,
+) -> (Client<Runtime>, Subscription<Header>) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let client = connect(chain_name, endpoint).await;
+        match client.subscribe_finalized_blocks().await {
+            Ok(headers) => return (client, headers),
+            Err(e) => warn!(
+                "[{}] failed to subscribe to finalized heads, retrying in {:?}; error = {}",
+                chain_name, backoff, e
+            ),
+        }
+        async_std::task::sleep(backoff).await;
+        backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+}
+
+/// Whether the node behind `client` still answers requests.
+///
+/// A dropped websocket does not end a subscription, it just never yields again, so a request is
+/// the only way to tell a quiet chain from a dead connection.
+pub async fn is_alive(client: &Client<Runtime>) -> bool {
+    matches!(
+        async_std::future::timeout(REQUEST_TIMEOUT, client.finalized_head()).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Returns the height of the last block finalized by the node behind `client`.
+pub async fn finalized_head_height(client: &Client<Runtime>) -> Result<u32, Box<dyn Error>> {
+    let hash = client.finalized_head().await?;
+    let header = client
+        .header(Some(hash))
+        .await?
+        .ok_or_else(|| format!("finalized header {:?} not found", hash))?;
+    Ok(header.number)
+}
@@ -0,0 +1,19 @@
+//! Helpers to describe datagrams in logs and metrics.
+use pallet_ibc::Datagram;
+
+/// Returns the name of the variant of `datagram`.
+pub fn kind(datagram: &Datagram) -> &'static str {
+    match datagram {
+        Datagram::ClientUpdate { .. } => "ClientUpdate",
+        Datagram::ClientMisbehaviour { .. } => "ClientMisbehaviour",
+        Datagram::ConnOpenTry { .. } => "ConnOpenTry",
+        Datagram::ConnOpenAck { .. } => "ConnOpenAck",
+        Datagram::ConnOpenConfirm { .. } => "ConnOpenConfirm",
+        Datagram::ChanOpenTry { .. } => "ChanOpenTry",
+        Datagram::ChanOpenAck { .. } => "ChanOpenAck",
+        Datagram::ChanOpenConfirm { .. } => "ChanOpenConfirm",
+        Datagram::PacketRecv { .. } => "PacketRecv",
+        Datagram::PacketAcknowledgement { .. } => "PacketAcknowledgement",
+        Datagram::PacketTimeout { .. } => "PacketTimeout",
+    }
+}
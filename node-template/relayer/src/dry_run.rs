@@ -0,0 +1,204 @@
+//! Writing datagrams as JSON lines instead of submitting them.
+use crate::datagram;
+use codec::Encode;
+use pallet_ibc::{Datagram, Packet};
+use serde_json::{json, Value};
+use sp_trie::StorageProof;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+/// Destination of the datagrams of a dry run, shared by all paths.
+#[derive(Clone)]
+pub struct DryRun {
+    output: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl DryRun {
+    pub fn new(output: Box<dyn Write + Send>) -> Self {
+        Self {
+            output: Arc::new(Mutex::new(output)),
+        }
+    }
+
+    /// Writes `datagram`, which the relayer would have submitted to `chain_name`, as one line.
+    pub fn write(&self, path: &str, chain_name: &str, datagram: &Datagram) -> io::Result<()> {
+        let line = json!({
+            "path": path,
+            "chain": chain_name,
+            "datagram": datagram::kind(datagram),
+            "fields": fields(datagram),
+            "scale": to_hex(datagram.encode()),
+        });
+        let mut output = self.output.lock().unwrap();
+        serde_json::to_writer(&mut *output, &line)?;
+        writeln!(output)?;
+        output.flush()
+    }
+}
+
+fn fields(datagram: &Datagram) -> Value {
+    match datagram {
+        Datagram::ClientUpdate { identifier, header } => json!({
+            "identifier": to_hex(identifier),
+            "height": header.height,
+            "block_hash": to_hex(header.block_hash),
+            "commitment_root": to_hex(header.commitment_root),
+            "justification": to_hex(&header.justification),
+            "authorities_proof": proof_nodes(&header.authorities_proof),
+        }),
+        Datagram::ClientMisbehaviour {
+            identifier,
+            evidence,
+        } => json!({
+            "identifier": to_hex(identifier),
+            "height": evidence.height,
+            "block_hash": to_hex(evidence.block_hash),
+            "commitment_root": to_hex(evidence.commitment_root),
+            "justification": to_hex(&evidence.justification),
+            "authorities_proof": proof_nodes(&evidence.authorities_proof),
+        }),
+        Datagram::ConnOpenTry {
+            desired_identifier,
+            counterparty_connection_identifier,
+            counterparty_client_identifier,
+            client_identifier,
+            version,
+            counterparty_version,
+            proof_init,
+            proof_consensus,
+            proof_height,
+            consensus_height,
+        } => json!({
+            "desired_identifier": to_hex(desired_identifier),
+            "counterparty_connection_identifier": to_hex(counterparty_connection_identifier),
+            "counterparty_client_identifier": to_hex(counterparty_client_identifier),
+            "client_identifier": to_hex(client_identifier),
+            "version": format!("{:?}", version),
+            "counterparty_version": format!("{:?}", counterparty_version),
+            "proof_init": proof_nodes(proof_init),
+            "proof_consensus": proof_nodes(proof_consensus),
+            "proof_height": proof_height,
+            "consensus_height": consensus_height,
+        }),
+        Datagram::ConnOpenAck {
+            identifier,
+            version,
+            proof_try,
+            proof_consensus,
+            proof_height,
+            consensus_height,
+        } => json!({
+            "identifier": to_hex(identifier),
+            "version": format!("{:?}", version),
+            "proof_try": proof_nodes(proof_try),
+            "proof_consensus": proof_nodes(proof_consensus),
+            "proof_height": proof_height,
+            "consensus_height": consensus_height,
+        }),
+        Datagram::ConnOpenConfirm {
+            identifier,
+            proof_ack,
+            proof_height,
+        } => json!({
+            "identifier": to_hex(identifier),
+            "proof_ack": proof_nodes(proof_ack),
+            "proof_height": proof_height,
+        }),
+        Datagram::ChanOpenTry {
+            order,
+            connection_hops,
+            port_identifier,
+            channel_identifier,
+            counterparty_port_identifier,
+            counterparty_channel_identifier,
+            version,
+            counterparty_version,
+            proof_init,
+            proof_height,
+        } => json!({
+            "order": format!("{:?}", order),
+            "connection_hops": connection_hops.iter().map(to_hex).collect::<Vec<_>>(),
+            "port_identifier": String::from_utf8_lossy(port_identifier),
+            "channel_identifier": to_hex(channel_identifier),
+            "counterparty_port_identifier": String::from_utf8_lossy(counterparty_port_identifier),
+            "counterparty_channel_identifier": to_hex(counterparty_channel_identifier),
+            "version": format!("{:?}", version),
+            "counterparty_version": format!("{:?}", counterparty_version),
+            "proof_init": proof_nodes(proof_init),
+            "proof_height": proof_height,
+        }),
+        Datagram::ChanOpenAck {
+            port_identifier,
+            channel_identifier,
+            version,
+            proof_try,
+            proof_height,
+        } => json!({
+            "port_identifier": String::from_utf8_lossy(port_identifier),
+            "channel_identifier": to_hex(channel_identifier),
+            "version": format!("{:?}", version),
+            "proof_try": proof_nodes(proof_try),
+            "proof_height": proof_height,
+        }),
+        Datagram::ChanOpenConfirm {
+            port_identifier,
+            channel_identifier,
+            proof_ack,
+            proof_height,
+        } => json!({
+            "port_identifier": String::from_utf8_lossy(port_identifier),
+            "channel_identifier": to_hex(channel_identifier),
+            "proof_ack": proof_nodes(proof_ack),
+            "proof_height": proof_height,
+        }),
+        Datagram::PacketRecv {
+            packet,
+            proof,
+            proof_height,
+        } => json!({
+            "packet": packet_fields(packet),
+            "proof": proof_nodes(proof),
+            "proof_height": proof_height,
+        }),
+        Datagram::PacketAcknowledgement {
+            packet,
+            acknowledgement,
+            proof,
+            proof_height,
+        } => json!({
+            "packet": packet_fields(packet),
+            "acknowledgement": to_hex(acknowledgement),
+            "proof": proof_nodes(proof),
+            "proof_height": proof_height,
+        }),
+        Datagram::PacketTimeout {
+            packet,
+            proof,
+            proof_height,
+        } => json!({
+            "packet": packet_fields(packet),
+            "proof": proof_nodes(proof),
+            "proof_height": proof_height,
+        }),
+    }
+}
+
+fn packet_fields(packet: &Packet) -> Value {
+    json!({
+        "sequence": packet.sequence,
+        "timeout_height": packet.timeout_height,
+        "source_port": String::from_utf8_lossy(&packet.source_port),
+        "source_channel": to_hex(packet.source_channel),
+        "dest_port": String::from_utf8_lossy(&packet.dest_port),
+        "dest_channel": to_hex(packet.dest_channel),
+        "data": to_hex(&packet.data),
+    })
+}
+
+fn proof_nodes(proof: &StorageProof) -> Vec<String> {
+    proof.clone().iter_nodes().map(to_hex).collect()
+}
+
+fn to_hex<T: AsRef<[u8]>>(bytes: T) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
@@ -0,0 +1,51 @@
+//! Restriction of a relay path to some ports, channels and connections.
+use serde_derive::Deserialize;
+use sp_core::H256;
+
+/// Which identifiers a relay path handles, as seen from its source chain.
+///
+/// An identifier passes if its allow list is empty or contains it, and its deny list does not.
+/// Channel and connection identifiers are hex strings, with or without a `0x` prefix.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PathFilter {
+    allow_ports: Vec<String>,
+    deny_ports: Vec<String>,
+    allow_channels: Vec<String>,
+    deny_channels: Vec<String>,
+    allow_connections: Vec<String>,
+    deny_connections: Vec<String>,
+}
+
+impl PathFilter {
+    pub fn allows_port(&self, port: &[u8]) -> bool {
+        let port = String::from_utf8_lossy(port);
+        passes(&self.allow_ports, &self.deny_ports, |allowed| {
+            allowed == port
+        })
+    }
+
+    /// Whether the channel `channel` of port `port` is relayed, which requires both to pass.
+    pub fn allows_channel(&self, port: &[u8], channel: H256) -> bool {
+        self.allows_port(port)
+            && passes(&self.allow_channels, &self.deny_channels, |allowed| {
+                same_identifier(allowed, channel)
+            })
+    }
+
+    pub fn allows_connection(&self, connection: H256) -> bool {
+        passes(&self.allow_connections, &self.deny_connections, |allowed| {
+            same_identifier(allowed, connection)
+        })
+    }
+}
+
+fn passes<F: Fn(&str) -> bool>(allow: &[String], deny: &[String], matches: F) -> bool {
+    (allow.is_empty() || allow.iter().any(|entry| matches(entry)))
+        && !deny.iter().any(|entry| matches(entry))
+}
+
+fn same_identifier(entry: &str, identifier: H256) -> bool {
+    let entry = entry.trim_start_matches("0x");
+    entry.eq_ignore_ascii_case(&hex::encode(identifier))
+}
@@ -0,0 +1,120 @@
+//! Connection and channel handshake steps submitted but not yet seen on the counterparty.
+use crate::datagram;
+use pallet_ibc::Datagram;
+use sp_core::H256;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a handshake step is left to take effect before it is sent again. The counterparty
+/// state is read at the height its client has reached, so a step can take a while to show up.
+const IN_FLIGHT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// The end of a connection or a channel a handshake step is submitted for.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum HandshakeEnd {
+    Connection(H256),
+    /// Channel of a port.
+    Channel(Vec<u8>, H256),
+}
+
+impl HandshakeEnd {
+    /// Returns the end `datagram` advances, if it is a handshake step.
+    pub fn of(datagram: &Datagram) -> Option<Self> {
+        match datagram {
+            Datagram::ConnOpenTry {
+                desired_identifier, ..
+            } => Some(HandshakeEnd::Connection(*desired_identifier)),
+            Datagram::ConnOpenAck { identifier, .. }
+            | Datagram::ConnOpenConfirm { identifier, .. } => {
+                Some(HandshakeEnd::Connection(*identifier))
+            }
+            Datagram::ChanOpenTry {
+                port_identifier,
+                channel_identifier,
+                ..
+            }
+            | Datagram::ChanOpenAck {
+                port_identifier,
+                channel_identifier,
+                ..
+            }
+            | Datagram::ChanOpenConfirm {
+                port_identifier,
+                channel_identifier,
+                ..
+            } => Some(HandshakeEnd::Channel(
+                port_identifier.clone(),
+                *channel_identifier,
+            )),
+            _ => None,
+        }
+    }
+}
+
+struct InFlight {
+    /// The kind of the handshake datagram.
+    step: &'static str,
+    since: Instant,
+}
+
+/// Handshake steps in flight on every chain, at most one per connection or channel end.
+///
+/// A step is in flight from the moment it is sent until the relayer sees its end move on, its
+/// submission fails, or it times out; only then is the same step sent again.
+pub struct InFlightHandshakes {
+    steps: HashMap<(String, HandshakeEnd), InFlight>,
+    timeout: Duration,
+}
+
+impl Default for InFlightHandshakes {
+    fn default() -> Self {
+        Self::new(IN_FLIGHT_TIMEOUT)
+    }
+}
+
+impl InFlightHandshakes {
+    /// Tracks steps that time out after `timeout`.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            steps: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Records `datagram` as sent to `chain`, unless the same step is already in flight.
+    ///
+    /// Returns whether the datagram should be sent; datagrams other than handshake steps always
+    /// are.
+    pub fn begin(&mut self, chain: &str, datagram: &Datagram) -> bool {
+        let end = match HandshakeEnd::of(datagram) {
+            Some(end) => end,
+            None => return true,
+        };
+        let step = datagram::kind(datagram);
+        let key = (chain.to_string(), end);
+        if let Some(in_flight) = self.steps.get(&key) {
+            if in_flight.step == step && in_flight.since.elapsed() < self.timeout {
+                return false;
+            }
+        }
+        self.steps.insert(
+            key,
+            InFlight {
+                step,
+                since: Instant::now(),
+            },
+        );
+        true
+    }
+
+    /// Forgets the step in flight for `end` on `chain`, once its submission failed or the end
+    /// no longer needs it.
+    pub fn settle(&mut self, chain: &str, end: HandshakeEnd) {
+        self.steps.remove(&(chain.to_string(), end));
+    }
+
+    /// Returns the number of steps in flight on `chain`.
+    pub fn len(&self, chain: &str) -> usize {
+        self.steps.keys().filter(|(name, _)| name == chain).count()
+    }
+}
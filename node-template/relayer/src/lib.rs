@@ -0,0 +1,21 @@
+//! Relaying of IBC datagrams between chains, independent of how the chains are reached.
+pub mod admin;
+pub mod chain;
+pub mod checkpoint;
+pub mod clear;
+pub mod client_update;
+pub mod config;
+pub mod connection;
+pub mod datagram;
+pub mod dry_run;
+pub mod filter;
+pub mod handshake;
+pub mod memory;
+pub mod metrics;
+pub mod misbehaviour;
+pub mod packets;
+pub mod proof;
+pub mod relay;
+pub mod signer;
+pub mod submitter;
+pub mod subxt_chain;
@@ -1,498 +1,733 @@
-use calls::{
-    ibc::{self, ChannelsStoreExt, ClientsStoreExt, ConnectionsStoreExt, SubmitDatagramCallExt},
-    NodeRuntime as Runtime,
-};
-use clap::{App, Arg, ArgMatches};
-use codec::Decode;
-use log::{debug, error, info};
-use pallet_ibc::{ChannelState, ConnectionState, Datagram, Header, Packet};
+mod check;
+
+use async_std::task::JoinHandle;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use futures::channel::mpsc;
+use futures::future;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use pallet_ibc::Datagram;
+use relayer::admin::{Admin, AdminPath};
+use relayer::chain::Chain;
+use relayer::checkpoint::CheckpointStore;
+use relayer::clear;
+use relayer::config::{parse_h256, ChainConfig};
+use relayer::dry_run::DryRun;
+use relayer::filter::PathFilter;
+use relayer::handshake::{HandshakeEnd, InFlightHandshakes};
+use relayer::metrics::Metrics;
+use relayer::misbehaviour;
+use relayer::packets::PendingPackets;
+use relayer::relay::{self, PathControl, RelayPath};
+use relayer::signer;
+use relayer::subxt_chain::SubxtChain;
 use serde_derive::Deserialize;
-use sp_core::{storage::StorageKey, twox_128, H256};
-use sp_finality_grandpa::GRANDPA_AUTHORITIES_KEY;
-use sp_keyring::AccountKeyring;
-use sp_runtime::generic;
-use sp_storage::StorageChangeSet;
-use sp_trie::StorageProof;
-use std::collections::HashMap;
+use signal_hook::iterator::Signals;
+use signal_hook::{SIGHUP, SIGINT, SIGTERM};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
-use std::io::Read;
-use std::sync::mpsc::{channel, Sender};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Duration;
-use substrate_subxt::{
-    system::{AccountStoreExt, System},
-    BlockNumber, Client, ClientBuilder, PairSigner, Store,
-};
+
+/// How long stopping paths waits for the datagrams they already sent to be submitted.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Deserialize)]
 struct Config {
     chains: HashMap<String, ChainConfig>,
     relay: Vec<RelayConfig>,
+    #[serde(default = "default_checkpoint_file")]
+    checkpoint_file: PathBuf,
+    /// How many blocks back from its checkpoint a path looks for the packets it was waiting on.
+    #[serde(default = "default_restore_depth")]
+    restore_depth: u32,
+    /// Address to serve Prometheus metrics on, if any.
+    metrics_address: Option<SocketAddr>,
+    /// Address to serve the admin interface on, if any.
+    admin_address: Option<SocketAddr>,
 }
 
-#[derive(Debug, Deserialize)]
-struct ChainConfig {
-    endpoint: String,
-    client_identifier: String,
+fn default_checkpoint_file() -> PathBuf {
+    PathBuf::from("relayer-checkpoints.toml")
 }
 
-#[derive(Debug, Deserialize)]
+fn default_restore_depth() -> u32 {
+    clear::DEFAULT_SCAN_DEPTH
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 struct RelayConfig {
     from: String,
     to: String,
+    /// The ports, channels and connections of `from` relayed on this path, all by default.
+    #[serde(default)]
+    filter: PathFilter,
+    /// Whether packets sent in blocks of `from` that are not finalized yet are tracked as pending
+    /// as soon as they are seen.
+    #[serde(default)]
+    unfinalized_events: bool,
 }
 
-type EventRecords = Vec<system::EventRecord<node_runtime::Event, <Runtime as System>::Hash>>;
-
-fn execute(matches: ArgMatches) {
+fn load_config(matches: &ArgMatches) -> Config {
     let file_path = matches.value_of("config").unwrap();
-    let mut file = File::open(file_path).expect("config.toml not found");
+    read_config(file_path).expect("can not load config.toml")
+}
+
+fn read_config(file_path: &str) -> Result<Config, Box<dyn Error>> {
+    let mut file = File::open(file_path)?;
     let mut contents = String::new();
-    file.read_to_string(&mut contents)
-        .expect("can not read config.toml");
-    let config: Config = toml::from_str(&contents).expect("can not parse config.toml");
-    println!("config: {:#?}", config);
-    let result = async_std::task::block_on(run(&config));
-    println!("run: {:?}", result);
+    file.read_to_string(&mut contents)?;
+    let config: Config = toml::from_str(&contents)?;
+    debug!("config: {:#?}", config);
+    Ok(config)
+}
+
+fn execute(matches: ArgMatches) {
+    match matches.subcommand() {
+        ("check", Some(matches)) => {
+            let config = load_config(matches);
+            if !async_std::task::block_on(check::check(&config)) {
+                process::exit(1);
+            }
+            return;
+        }
+        ("monitor", Some(matches)) => {
+            let config = load_config(matches);
+            if let Err(e) = async_std::task::block_on(monitor(&config)) {
+                error!("monitor: {}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        ("clear-packets", Some(matches)) => {
+            let config = load_config(matches);
+            if let Err(e) = async_std::task::block_on(clear_packets(&config, matches)) {
+                error!("clear-packets: {}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    let config = load_config(&matches);
+    let dry_run = if matches.is_present("dry-run") {
+        let output: Box<dyn Write + Send> = match matches.value_of("dry-run-output") {
+            Some(file_path) => {
+                Box::new(File::create(file_path).expect("can not create output file"))
+            }
+            None => Box::new(io::stdout()),
+        };
+        Some(DryRun::new(output))
+    } else {
+        None
+    };
+    let config_path = matches.value_of("config").unwrap();
+    if let Err(e) = async_std::task::block_on(run(config_path, &config, dry_run)) {
+        error!("run: {}", e);
+        process::exit(1);
+    }
 }
 
 fn print_usage(matches: &ArgMatches) {
     println!("{}", matches.usage());
 }
 
+fn config_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("config")
+        .short("c")
+        .long("config")
+        .value_name("FILE")
+        .help("Sets a custom config file")
+        .takes_value(true)
+        .required(true)
+}
+
 fn main() {
     env_logger::init();
     let matches = App::new("relayer")
         .author("Cdot Network <ys@cdot.network>")
         .about("Relayer is an off-chain process to relay IBC packets between chains")
         .version(env!("CARGO_PKG_VERSION"))
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(config_arg())
         .arg(
-            Arg::with_name("config")
-                .short("c")
-                .long("config")
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Writes the datagrams as JSON lines instead of submitting them"),
+        )
+        .arg(
+            Arg::with_name("dry-run-output")
+                .long("dry-run-output")
                 .value_name("FILE")
-                .help("Sets a custom config file")
+                .help("Writes the datagrams of a dry run to FILE instead of stdout")
                 .takes_value(true)
-                .required(true),
+                .requires("dry-run"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Checks the configured chains, clients and signers against the live chains")
+                .arg(config_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("monitor")
+                .about("Watches the clients for misbehaviour and submits the evidence")
+                .arg(config_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("clear-packets")
+                .about("Relays the packets and acknowledgements a channel left outstanding")
+                .arg(config_arg())
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .value_name("FROM:TO")
+                        .help("Sets the chain the packets were sent from and the chain they go to")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("channel")
+                        .long("channel")
+                        .value_name("IDENTIFIER")
+                        .help("Sets the channel of the source chain, in hex")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("port")
+                        .long("port")
+                        .value_name("PORT")
+                        .help("Sets the port of the channel, looked up on the source chain by default")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("from-height")
+                        .long("from-height")
+                        .value_name("HEIGHT")
+                        .help("Sets the first block of the source chain scanned for sent packets, 14400 blocks before the finalized one by default")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("counterparty-from-height")
+                        .long("counterparty-from-height")
+                        .value_name("HEIGHT")
+                        .help("Sets the first block of the destination chain scanned for received packets, 14400 blocks before the finalized one by default")
+                        .takes_value(true),
+                ),
         )
         .get_matches();
     execute(matches);
 }
 
-async fn run(config: &Config) -> Result<(), Box<dyn Error>> {
-    async_std::task::block_on(async {
-        for task in &config.relay {
-            println!("task: {:?}", task);
-            let from = task.from.clone();
-            let from_endpoint = &config.chains[&from].endpoint;
-            let from_client_identifier = hex::decode(&config.chains[&from].client_identifier)
-                .and_then(|identifier| Ok(H256::from_slice(&identifier)))
-                .unwrap();
-
-            let to = task.to.clone();
-            let to_endpoint = &config.chains[&to].endpoint;
-            let to_client_identifier = hex::decode(&config.chains[&to].client_identifier)
-                .and_then(|identifier| Ok(H256::from_slice(&identifier)))
-                .unwrap();
+async fn clear_packets(config: &Config, matches: &ArgMatches<'_>) -> Result<(), Box<dyn Error>> {
+    let path_name = matches.value_of("path").unwrap();
+    let mut chains = path_name.splitn(2, ':');
+    let (from_name, to_name) = match (chains.next(), chains.next()) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Err(format!("path {} is not of the form FROM:TO", path_name).into()),
+    };
+    let from_config = config
+        .chains
+        .get(from_name)
+        .ok_or_else(|| format!("chain {} not configured", from_name))?;
+    let to_config = config
+        .chains
+        .get(to_name)
+        .ok_or_else(|| format!("chain {} not configured", to_name))?;
+    let channel = matches.value_of("channel").unwrap();
+    let channel = parse_h256(channel, "channel identifier")?;
+    let from_height = match matches.value_of("from-height") {
+        Some(height) => Some(height.parse::<u32>()?),
+        None => None,
+    };
+    let counterparty_from_height = match matches.value_of("counterparty-from-height") {
+        Some(height) => Some(height.parse::<u32>()?),
+        None => None,
+    };
+    let from_client_identifier = parse_h256(&from_config.client_identifier, "client identifier")
+        .map_err(|e| format!("client identifier of {} is not valid: {}", from_name, e))?;
+    let to_client_identifier = parse_h256(&to_config.client_identifier, "client identifier")
+        .map_err(|e| format!("client identifier of {} is not valid: {}", to_name, e))?;
 
-            let from_client = ClientBuilder::<Runtime>::new()
-                .set_url(from_endpoint)
-                .build()
-                .await?;
-            let to_client = ClientBuilder::<Runtime>::new()
-                .set_url(to_endpoint)
-                .build()
-                .await?;
-
-            let mut from_block_headers = from_client.subscribe_finalized_blocks().await?;
+    let metrics = Metrics::new()?;
+    // Packets go to the destination and their acknowledgements back to the source.
+    let mut from = SubxtChain::connect(from_name, &from_config.endpoint).await;
+    from.set_signer(signer::load_pair(&from_config.signer)?, metrics.clone())
+        .await?;
+    let mut to = SubxtChain::connect(to_name, &to_config.endpoint).await;
+    to.set_signer(signer::load_pair(&to_config.signer)?, metrics.clone())
+        .await?;
+    let path = RelayPath {
+        from,
+        from_client_identifier,
+        to,
+        to_client_identifier,
+        filter: PathFilter::default(),
+        pending_packets: Default::default(),
+        handshakes: Default::default(),
+        metrics,
+    };
+    let port = match matches.value_of("port") {
+        Some(port) => port.as_bytes().to_vec(),
+        None => clear::channel_port(&path.from, from_client_identifier, channel).await?,
+    };
 
-            let (tx, rx) = channel();
+    let summary =
+        clear::clear_packets(&path, port, channel, from_height, counterparty_from_height).await?;
+    println!(
+        "relayed {} packet(s) to {}: {:?}",
+        summary.received.len(),
+        to_name,
+        summary.received
+    );
+    println!(
+        "relayed {} acknowledgement(s) to {}: {:?}",
+        summary.acknowledged.len(),
+        from_name,
+        summary.acknowledged
+    );
+    if !summary.skipped.is_empty() {
+        println!(
+            "skipped {} packet(s): {:?}",
+            summary.skipped.len(),
+            summary.skipped
+        );
+    }
+    Ok(())
+}
 
-            let from_client = from_client.clone();
-            {
-                let to_client = to_client.clone();
-                async_std::task::spawn(async move {
-                    loop {
-                        let block_header = from_block_headers.next().await;
-                        let tx = tx.clone();
-                        if let Err(e) = relay(
-                            &from,
-                            tx,
-                            block_header,
-                            &from_client,
-                            from_client_identifier,
-                            &to_client,
-                            to_client_identifier,
-                        )
-                        .await
-                        {
-                            error!("[{}] failed to relay; error = {}", from, e);
-                        }
-                    }
-                });
+/// Connects to the chains of the relay paths missing from `chains`, setting the signers of the
+/// chains datagrams are submitted to if `sign` is set.
+///
+/// There is one connection, finalized-head subscription and submitter per chain, whatever the
+/// number of paths it is on.
+async fn connect_chains(
+    config: &Config,
+    chains: &mut HashMap<String, SubxtChain>,
+    sign: bool,
+    metrics: &Metrics,
+) -> Result<(), Box<dyn Error>> {
+    for task in &config.relay {
+        for name in [&task.from, &task.to].iter() {
+            if !config.chains.contains_key(*name) {
+                return Err(format!("chain {} not configured", name).into());
+            }
+        }
+    }
+    for task in &config.relay {
+        for name in [&task.from, &task.to].iter() {
+            if !chains.contains_key(*name) {
+                let endpoint = &config.chains[*name].endpoint;
+                chains.insert(name.to_string(), SubxtChain::connect(name, endpoint).await);
             }
+        }
+    }
+    if sign {
+        for task in &config.relay {
+            let to = chains.get_mut(&task.to).unwrap();
+            if !to.has_signer() {
+                to.set_signer(
+                    signer::load_pair(&config.chains[&task.to].signer)?,
+                    metrics.clone(),
+                )
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
 
-            async_std::task::spawn(async move {
-                let mut signer = PairSigner::new(AccountKeyring::Alice.pair());
-                let nonce = to_client
-                    .account(&AccountKeyring::Alice.to_account_id(), None)
-                    .await
-                    .unwrap()
-                    .nonce;
-                signer.set_nonce(nonce);
-                loop {
-                    let datagram = rx.recv().unwrap();
-                    match datagram {
-                        Datagram::ClientUpdate { .. } => {
-                            debug!("[relayer => {}] datagram: {:?}", to, datagram)
-                        }
-                        _ => debug!("[relayer => {}] datagram: {:#?}", to, datagram),
-                    }
-                    if let Err(e) = to_client.submit_datagram(&signer, datagram).await {
-                        error!("[relayer => {}] failed to send datagram; error = {}", to, e);
-                    }
-                    signer.increment_nonce();
-                }
-            });
+/// Watches the client of the source chain of every relay path for misbehaviour, instead of
+/// relaying, until SIGINT or SIGTERM.
+async fn monitor(config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut signals = signals()?;
+    let metrics = Metrics::new()?;
+    let mut chains = HashMap::new();
+    connect_chains(config, &mut chains, true, &metrics).await?;
+    for task in &config.relay {
+        let to_client_identifier = parse_h256(
+            &config.chains[&task.to].client_identifier,
+            "client identifier",
+        )
+        .map_err(|e| format!("client identifier of {} is not valid: {}", task.to, e))?;
+        let from_client_identifier = parse_h256(
+            &config.chains[&task.from].client_identifier,
+            "client identifier",
+        )
+        .map_err(|e| format!("client identifier of {} is not valid: {}", task.from, e))?;
+        let mut from = chains[&task.from].clone();
+        let block_headers = from.finalized_heads().await;
+        let path = RelayPath {
+            from,
+            from_client_identifier,
+            to: chains[&task.to].clone(),
+            to_client_identifier,
+            filter: task.filter.clone(),
+            pending_packets: Default::default(),
+            handshakes: Default::default(),
+            metrics: metrics.clone(),
+        };
+        info!(
+            "[{}] monitoring client {:?} of {}",
+            task.to, to_client_identifier, task.from
+        );
+        async_std::task::spawn(misbehaviour::monitor(path, block_headers));
+    }
+    // There is nothing to reload, SIGHUP is ignored.
+    while let Some(signal) = signals.next().await {
+        if signal != SIGHUP {
+            info!("shutting down");
+            break;
         }
-        loop {
-            async_std::task::sleep(Duration::from_secs(60 * 60)).await;
+    }
+    Ok(())
+}
+
+/// Forwards the signals the relayer handles, SIGINT and SIGTERM to stop and SIGHUP to reload its
+/// configuration.
+fn signals() -> Result<mpsc::UnboundedReceiver<i32>, Box<dyn Error>> {
+    let signals = Signals::new(&[SIGHUP, SIGINT, SIGTERM])?;
+    let (tx, rx) = mpsc::unbounded();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if tx.unbounded_send(signal).is_err() {
+                break;
+            }
         }
-    })
+    });
+    Ok(rx)
 }
 
-async fn relay(
-    chain_name: &str,
-    tx: Sender<Datagram>,
-    block_header: generic::Header<u32, sp_runtime::traits::BlakeTwo256>,
-    client: &Client<Runtime>,
-    client_identifier: H256,
-    counterparty_client: &Client<Runtime>,
-    counterparty_client_identifier: H256,
-) -> Result<(), Box<dyn Error>> {
-    let mut storage_key = twox_128(b"System").to_vec();
-    storage_key.extend(twox_128(b"Events").to_vec());
-    let events_storage_key = StorageKey(storage_key);
+/// A relay path followed by `run`.
+struct RunningPath {
+    config: RelayConfig,
+    admin_path: AdminPath<SubxtChain>,
+    /// The tasks following the path, watching its unfinalized blocks and submitting its
+    /// datagrams, which all end once the path is stopped.
+    tasks: Vec<JoinHandle<()>>,
+}
 
-    let block_number = block_header.number;
-    let state_root = block_header.state_root;
-    let block_hash = block_header.hash();
-    debug!("[{}] block_number: {}", chain_name, block_number);
-    debug!("[{}] state_root: {:?}", chain_name, state_root);
-    debug!("[{}] block_hash: {:?}", chain_name, block_hash);
-    let client_state = client.clients(client_identifier, Some(block_hash)).await?;
+/// The relay paths followed by `run`, and what they share.
+struct Relayer {
+    chains: HashMap<String, SubxtChain>,
+    paths: Vec<RunningPath>,
+    checkpoints: Arc<Mutex<CheckpointStore>>,
+    pending_packets: Arc<Mutex<PendingPackets>>,
+    handshakes: Arc<Mutex<InFlightHandshakes>>,
+    metrics: Metrics,
+    admin: Admin<SubxtChain>,
+    dry_run: Option<DryRun>,
+}
 
-    let counterparty_block_hash = counterparty_client
-        .block_hash(Some(BlockNumber::from(client_state.latest_height)))
-        .await?;
-    info!(
-        "[{}] client latest height: {}",
-        chain_name, client_state.latest_height
-    );
-    let counterparty_client_state = counterparty_client
-        .clients(counterparty_client_identifier, None)
+impl Relayer {
+    /// Starts the relay paths of `config` that are not running yet.
+    async fn start_paths(&mut self, config: &Config) -> Result<(), Box<dyn Error>> {
+        connect_chains(
+            config,
+            &mut self.chains,
+            self.dry_run.is_none(),
+            &self.metrics,
+        )
         .await?;
-    if counterparty_client_state.latest_height < block_number {
-        for height in counterparty_client_state.latest_height + 1..=block_number {
-            let hash = client.block_hash(Some(BlockNumber::from(height))).await?;
-            let signed_block = client.block(hash).await?;
-            let authorities_proof = client
-                .read_proof(
-                    vec![StorageKey(GRANDPA_AUTHORITIES_KEY.to_vec())],
-                    Some(hash.unwrap()),
-                )
-                .await?;
-            if let Some(signed_block) = signed_block {
-                if let Some(justification) = signed_block.justification {
-                    let datagram = Datagram::ClientUpdate {
-                        identifier: counterparty_client_identifier,
-                        header: Header {
-                            height: signed_block.block.header.number,
-                            block_hash: signed_block.block.header.hash(),
-                            commitment_root: signed_block.block.header.state_root,
-                            justification,
-                            authorities_proof: StorageProof::new(
-                                authorities_proof.proof.into_iter().map(|b| b.0).collect(),
-                            ),
-                        },
-                    };
-                    tx.send(datagram).unwrap();
-                }
+        // Pending packets are timed out by the opposite path, without it they would pile up.
+        let mut tracked = HashSet::new();
+        for task in &config.relay {
+            let opposite = config
+                .relay
+                .iter()
+                .any(|other| other.from == task.to && other.to == task.from);
+            if opposite {
+                tracked.insert((task.from.clone(), task.to.clone()));
+            } else {
+                warn!(
+                    "[{}:{}] no path from {} to {}, packets sent on {} will not be timed out",
+                    task.from, task.to, task.to, task.from, task.from
+                );
             }
         }
-    }
-    if client_state.connections.len() > 0 {
-        info!(
-            "[{}] connections: {:?}",
-            chain_name, client_state.connections
+        self.pending_packets.lock().unwrap().track(tracked);
+        for task in &config.relay {
+            if self.paths.iter().all(|running| running.config != *task) {
+                let running = self.start_path(config, task).await?;
+                self.paths.push(running);
+            }
+        }
+        self.admin.set_paths(
+            self.paths
+                .iter()
+                .map(|running| running.admin_path.clone())
+                .collect(),
         );
+        Ok(())
     }
-    for connection in client_state.connections.iter() {
-        let connection_end = client.connections(*connection, Some(block_hash)).await?;
-        debug!("[{}] connection_end: {:#?}", chain_name, connection_end);
-        let remote_connection_end = counterparty_client
-            .connections(
-                connection_end.counterparty_connection_identifier,
-                counterparty_block_hash,
-            )
-            .await?;
-        debug!(
-            "[{}] remote_connection_end: {:#?}",
-            chain_name, remote_connection_end
-        );
-        info!(
-            "[{}] connection state: {:?}, counterparty connection state: {:?}",
-            chain_name, connection_end.state, remote_connection_end.state
-        );
-        // TODO: remote_connection_end == None ??
-        if connection_end.state == ConnectionState::Init
-            && remote_connection_end.state == ConnectionState::None
-        {
-            let consensus_states = ibc::ConsensusStatesStore::<Runtime> {
-                key: (client_identifier, block_number),
-                _runtime: Default::default(),
-            };
-            let key = consensus_states.key(&client.metadata())?;
-            let proof_consensus = client.read_proof(vec![key], Some(block_hash)).await?;
-            let connections = ibc::ConnectionsStore::<Runtime> {
-                key: *connection,
-                _runtime: Default::default(),
-            };
-            let key = connections.key(&client.metadata())?;
-            let proof_init = client.read_proof(vec![key], Some(block_hash)).await?;
-            let datagram = Datagram::ConnOpenTry {
-                desired_identifier: connection_end.counterparty_connection_identifier,
-                counterparty_connection_identifier: *connection,
-                counterparty_client_identifier: client_identifier,
-                client_identifier: counterparty_client_identifier,
-                version: vec![],
-                counterparty_version: vec![],
-                proof_init: StorageProof::new(proof_init.proof.into_iter().map(|b| b.0).collect()),
-                proof_consensus: StorageProof::new(
-                    proof_consensus.proof.into_iter().map(|b| b.0).collect(),
-                ),
-                proof_height: block_number,
-                consensus_height: 0, // TODO: local consensus state height
-            };
-            tx.send(datagram).unwrap();
-        } else if connection_end.state == ConnectionState::TryOpen
-            && remote_connection_end.state == ConnectionState::Init
-        {
-            let connections = ibc::ConnectionsStore::<Runtime> {
-                key: *connection,
-                _runtime: Default::default(),
-            };
-            let key = connections.key(&client.metadata())?;
-            let proof_try = client.read_proof(vec![key], Some(block_hash)).await?;
-            let datagram = Datagram::ConnOpenAck {
-                identifier: connection_end.counterparty_connection_identifier,
-                version: vec![],
-                proof_try: StorageProof::new(proof_try.proof.into_iter().map(|b| b.0).collect()),
-                proof_consensus: StorageProof::empty(),
-                proof_height: block_number,
-                consensus_height: 0,
-            };
-            tx.send(datagram).unwrap();
-        } else if connection_end.state == ConnectionState::Open
-            && remote_connection_end.state == ConnectionState::TryOpen
-        {
-            let connections = ibc::ConnectionsStore::<Runtime> {
-                key: *connection,
-                _runtime: Default::default(),
-            };
-            let key = connections.key(&client.metadata())?;
-            let proof_ack = client.read_proof(vec![key], Some(block_hash)).await?;
-            let datagram = Datagram::ConnOpenConfirm {
-                identifier: connection_end.counterparty_connection_identifier,
-                proof_ack: StorageProof::new(proof_ack.proof.into_iter().map(|b| b.0).collect()),
-                proof_height: block_number,
-            };
-            tx.send(datagram).unwrap();
+
+    async fn start_path(
+        &self,
+        config: &Config,
+        task: &RelayConfig,
+    ) -> Result<RunningPath, Box<dyn Error>> {
+        info!("task: {:?}", task);
+        let from_client_identifier = parse_h256(
+            &config.chains[&task.from].client_identifier,
+            "client identifier",
+        )
+        .map_err(|e| format!("client identifier of {} is not valid: {}", task.from, e))?;
+        let to_client_identifier = parse_h256(
+            &config.chains[&task.to].client_identifier,
+            "client identifier",
+        )
+        .map_err(|e| format!("client identifier of {} is not valid: {}", task.to, e))?;
+
+        let mut from = self.chains[&task.from].clone();
+        let block_headers = from.finalized_heads().await;
+        let to = self.chains[&task.to].clone();
+
+        // Subscribed before catching up, so that no block finalized in between is missed.
+        let finalized_height = from.finalized_height().await?;
+        let checkpoint = self.checkpoints.lock().unwrap().get(&task.from, &task.to);
+        // Without a checkpoint there is nothing to catch up with, start from the current head.
+        let last_height = checkpoint.unwrap_or(finalized_height.saturating_sub(1));
+        if last_height < finalized_height {
+            info!(
+                "[{}] catching up from #{} to #{}",
+                task.from,
+                last_height + 1,
+                finalized_height
+            );
         }
+
+        let mut tasks = vec![];
+        let (tx, mut rx) = mpsc::unbounded();
+        let path_name = format!("{}:{}", task.from, task.to);
+        match &self.dry_run {
+            Some(dry_run) => {
+                let dry_run = dry_run.clone();
+                let to = to.clone();
+                tasks.push(async_std::task::spawn(async move {
+                    while let Some(datagram) = rx.next().await {
+                        if let Err(e) = dry_run.write(&path_name, to.name(), &datagram) {
+                            error!(
+                                "[relayer => {}] failed to write datagram; error = {}",
+                                to.name(),
+                                e
+                            );
+                        }
+                    }
+                }));
+            }
+            None => {
+                let to = to.clone();
+                let from_name = task.from.clone();
+                let handshakes = self.handshakes.clone();
+                let pending_packets = self.pending_packets.clone();
+                // Queues the datagrams of the path as they come, following their outcomes on the
+                // side so that a datagram waiting for inclusion does not hold back the next ones.
+                tasks.push(async_std::task::spawn(async move {
+                    let mut outcomes = FuturesUnordered::new();
+                    loop {
+                        let datagram = futures::select! {
+                            datagram = rx.next() => match datagram {
+                                Some(datagram) => datagram,
+                                None => break,
+                            },
+                            () = outcomes.select_next_some() => continue,
+                        };
+                        match datagram {
+                            Datagram::ClientUpdate { .. } => {
+                                debug!("[relayer => {}] datagram: {:?}", to.name(), datagram)
+                            }
+                            _ => debug!("[relayer => {}] datagram: {:#?}", to.name(), datagram),
+                        }
+                        let end = HandshakeEnd::of(&datagram);
+                        let timed_out = match &datagram {
+                            Datagram::PacketTimeout { packet, .. } => Some(packet.clone()),
+                            _ => None,
+                        };
+                        let submission = to.submit(&path_name, datagram);
+                        let to = to.clone();
+                        let from_name = from_name.clone();
+                        let handshakes = handshakes.clone();
+                        let pending_packets = pending_packets.clone();
+                        outcomes.push(async move {
+                            if let Err(e) = submission.await {
+                                error!(
+                                    "[relayer => {}] failed to submit datagram; error = {}",
+                                    to.name(),
+                                    e
+                                );
+                                // Lets the next block send the step again.
+                                if let Some(end) = end {
+                                    handshakes.lock().unwrap().settle(to.name(), end);
+                                }
+                                if let Some(packet) = timed_out {
+                                    pending_packets.lock().unwrap().set_timing_out(
+                                        to.name(),
+                                        &from_name,
+                                        &packet,
+                                        false,
+                                    );
+                                }
+                            }
+                        });
+                    }
+                    // The path stopped, wait for what it already sent.
+                    while outcomes.next().await.is_some() {}
+                }));
+            }
+        }
+
+        let path = RelayPath {
+            from,
+            from_client_identifier,
+            to,
+            to_client_identifier,
+            filter: task.filter.clone(),
+            pending_packets: self.pending_packets.clone(),
+            handshakes: self.handshakes.clone(),
+            metrics: self.metrics.clone(),
+        };
+        match relay::restore_pending_packets(&path, last_height, config.restore_depth).await {
+            Ok(restored) => info!("[{}] {} packet(s) pending", path.name(), restored),
+            Err(e) => error!(
+                "[{}] failed to restore the pending packets; error = {}",
+                path.name(),
+                e
+            ),
+        }
+        let control = Arc::new(PathControl::default());
+        if task.unfinalized_events {
+            tasks.push(async_std::task::spawn(relay::watch_unfinalized(
+                path.clone(),
+                control.clone(),
+            )));
+        }
+        let admin_path = AdminPath {
+            path: path.clone(),
+            control: control.clone(),
+        };
+        tasks.push(async_std::task::spawn(relay::follow(
+            path,
+            block_headers,
+            tx,
+            self.checkpoints.clone(),
+            control,
+            last_height,
+            finalized_height,
+        )));
+        Ok(RunningPath {
+            config: task.clone(),
+            admin_path,
+            tasks,
+        })
     }
-    if client_state.channels.len() > 0 {
-        info!("[{}] channels: {:?}", chain_name, client_state.channels);
-    }
-    for channel in client_state.channels.iter() {
-        let channel_end = client.channels(channel.clone(), Some(block_hash)).await?;
 
-        debug!("[{}] channel_end: {:#?}", chain_name, channel_end);
-        let remote_channel_end = counterparty_client
-            .channels(
-                (
-                    channel_end.counterparty_port_identifier.clone(),
-                    channel_end.counterparty_channel_identifier,
-                ),
-                counterparty_block_hash,
-            )
-            .await?;
-        debug!(
-            "[{}] remote_channel_end: {:#?}",
-            chain_name, remote_channel_end
-        );
-        info!(
-            "[{}] channle state: {:?}, counterparty channel state: {:?}",
-            chain_name, channel_end.state, remote_channel_end.state
-        );
-        if channel_end.state == ChannelState::Init && remote_channel_end.state == ChannelState::None
-        {
-            let connection_end = client
-                .connections(channel_end.connection_hops[0], Some(block_hash))
-                .await?;
-            let channels = ibc::ChannelsStore::<Runtime> {
-                key: channel.clone(),
-                _runtime: Default::default(),
-            };
-            let key = channels.key(&client.metadata())?;
-            let proof_init = client.read_proof(vec![key], Some(block_hash)).await?;
-            let datagram = Datagram::ChanOpenTry {
-                order: channel_end.ordering,
-                // connection_hops: channel_end.connection_hops.into_iter().rev().collect(), // ??
-                connection_hops: vec![connection_end.counterparty_connection_identifier],
-                port_identifier: channel_end.counterparty_port_identifier,
-                channel_identifier: channel_end.counterparty_channel_identifier,
-                counterparty_port_identifier: channel.0.clone(),
-                counterparty_channel_identifier: channel.1,
-                version: channel_end.version.clone(),
-                counterparty_version: channel_end.version,
-                proof_init: StorageProof::new(proof_init.proof.into_iter().map(|b| b.0).collect()),
-                proof_height: block_number,
-            };
-            tx.send(datagram).unwrap();
-        } else if channel_end.state == ChannelState::TryOpen
-            && remote_channel_end.state == ChannelState::Init
-        {
-            let channels = ibc::ChannelsStore::<Runtime> {
-                key: channel.clone(),
-                _runtime: Default::default(),
-            };
-            let key = channels.key(&client.metadata())?;
-            let proof_try = client.read_proof(vec![key], Some(block_hash)).await?;
-            let datagram = Datagram::ChanOpenAck {
-                port_identifier: channel_end.counterparty_port_identifier,
-                channel_identifier: channel_end.counterparty_channel_identifier,
-                version: remote_channel_end.version,
-                proof_try: StorageProof::new(proof_try.proof.into_iter().map(|b| b.0).collect()),
-                proof_height: block_number,
-            };
-            tx.send(datagram).unwrap();
-        } else if channel_end.state == ChannelState::Open
-            && remote_channel_end.state == ChannelState::TryOpen
+    /// Stops `paths`, waiting for the datagrams they already sent to be submitted.
+    ///
+    /// Gives up waiting after `DRAIN_TIMEOUT`, as submitting to a node that went away retries
+    /// until it is back, leaving the datagrams not submitted yet behind.
+    async fn stop_paths(&self, paths: Vec<RunningPath>) {
+        for running in &paths {
+            info!("[{}] stopping", running.admin_path.path.name());
+            running.admin_path.control.stop();
+        }
+        let drained = future::join_all(paths.into_iter().flat_map(|running| running.tasks));
+        if async_std::future::timeout(DRAIN_TIMEOUT, drained)
+            .await
+            .is_err()
         {
-            let channels = ibc::ChannelsStore::<Runtime> {
-                key: channel.clone(),
-                _runtime: Default::default(),
-            };
-            let key = channels.key(&client.metadata())?;
-            let proof_ack = client.read_proof(vec![key], Some(block_hash)).await?;
-            let datagram = Datagram::ChanOpenConfirm {
-                port_identifier: channel_end.counterparty_port_identifier,
-                channel_identifier: channel_end.counterparty_channel_identifier,
-                proof_ack: StorageProof::new(proof_ack.proof.into_iter().map(|b| b.0).collect()),
-                proof_height: block_number,
-            };
-            tx.send(datagram).unwrap();
+            warn!(
+                "gave up waiting for the datagrams of the stopped paths after {:?}",
+                DRAIN_TIMEOUT
+            );
         }
     }
 
-    let change_sets: Vec<StorageChangeSet<H256>> = client
-        .query_storage(vec![events_storage_key], block_hash, None)
+    /// Moves over to the relay paths of `config`.
+    ///
+    /// Paths whose configuration is unchanged keep running; paths that are gone or changed are
+    /// stopped, and changed ones started again from their checkpoint. Chains already connected
+    /// keep their connection, so changes to their `[chains]` entry need a restart.
+    async fn reload(&mut self, config: &Config) -> Result<(), Box<dyn Error>> {
+        // Connect first, so that a configuration naming unknown chains leaves every path running.
+        connect_chains(
+            config,
+            &mut self.chains,
+            self.dry_run.is_none(),
+            &self.metrics,
+        )
         .await?;
-    debug!("length of change_sets: {:?}", change_sets.len());
-    debug!("change_sets: {:?}", change_sets);
-    let events = change_sets
-        .into_iter()
-        .map(|change_set| change_set.changes)
-        .flatten()
-        .filter_map(|(_key, data)| data.as_ref().map(|data| Decode::decode(&mut &data.0[..])))
-        .filter_map(|result: Result<EventRecords, codec::Error>| result.ok())
-        .flatten()
-        .collect::<Vec<system::EventRecord<node_runtime::Event, <Runtime as System>::Hash>>>();
-    for event in events.into_iter() {
-        match event.event {
-            node_runtime::Event::ibc(pallet_ibc::RawEvent::SendPacket(
-                sequence,
-                data,
-                timeout_height,
-                source_port,
-                source_channel,
-                dest_port,
-                dest_channel,
-            )) => {
-                info!("[{}] SendPacket data: {:?}", chain_name, data);
-                let packet_data = Packet {
-                    sequence,
-                    timeout_height,
-                    source_port: source_port.clone(),
-                    source_channel,
-                    dest_port,
-                    dest_channel,
-                    data,
-                };
-                let packets = ibc::PacketsStore::<Runtime> {
-                    key: (source_port, source_channel, timeout_height.into()),
-                    _runtime: Default::default(),
-                };
-                let key = packets.key(&client.metadata())?;
-                let proof = client.read_proof(vec![key], Some(block_hash)).await?;
-                let datagram = Datagram::PacketRecv {
-                    packet: packet_data,
-                    proof: StorageProof::new(proof.proof.into_iter().map(|b| b.0).collect()),
-                    proof_height: block_number,
-                };
-                tx.send(datagram).unwrap();
+        let (kept, removed): (Vec<_>, Vec<_>) = self
+            .paths
+            .drain(..)
+            .partition(|running| config.relay.contains(&running.config));
+        self.paths = kept;
+        self.stop_paths(removed).await;
+        self.start_paths(config).await
+    }
+
+    /// Stops every path, then writes the checkpoints one last time even if some datagrams could
+    /// not be submitted in time.
+    async fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        let paths = self.paths.drain(..).collect();
+        self.stop_paths(paths).await;
+        self.checkpoints.lock().unwrap().flush()
+    }
+}
+
+/// Relays the paths of `config`, read from `config_path`, until SIGINT or SIGTERM; SIGHUP reads
+/// `config_path` again and moves over to the relay paths it lists.
+async fn run(
+    config_path: &str,
+    config: &Config,
+    dry_run: Option<DryRun>,
+) -> Result<(), Box<dyn Error>> {
+    // A dry run must not move the checkpoints past blocks it did not actually relay.
+    let checkpoints = match dry_run {
+        Some(_) => CheckpointStore::open_read_only(&config.checkpoint_file)?,
+        None => CheckpointStore::open(&config.checkpoint_file)?,
+    };
+    let metrics = Metrics::new()?;
+    if let Some(address) = config.metrics_address {
+        async_std::task::spawn(metrics.clone().serve(address));
+    }
+    let mut signals = signals()?;
+    let mut relayer = Relayer {
+        chains: HashMap::new(),
+        paths: vec![],
+        checkpoints: Arc::new(Mutex::new(checkpoints)),
+        pending_packets: Arc::new(Mutex::new(PendingPackets::default())),
+        handshakes: Arc::new(Mutex::new(InFlightHandshakes::default())),
+        metrics,
+        admin: Admin::new(vec![]),
+        dry_run,
+    };
+    async_std::task::block_on(async {
+        relayer.start_paths(config).await?;
+        if let Some(address) = config.admin_address {
+            async_std::task::spawn(relayer.admin.clone().serve(address));
+        }
+        while let Some(signal) = signals.next().await {
+            if signal != SIGHUP {
+                info!("shutting down");
+                break;
             }
-            node_runtime::Event::ibc(pallet_ibc::RawEvent::RecvPacket(
-                sequence,
-                data,
-                timeout_height,
-                source_port,
-                source_channel,
-                dest_port,
-                dest_channel,
-                acknowledgement,
-            )) => {
-                debug!(
-                    "[{}] RecvPacket sequence: {}, data: {:?}, timeout_height: {}, \
-                             source_port: {:?}, source_channel: {:?}, dest_port: {:?}, \
-                             dest_channel: {:?}",
-                    chain_name,
-                    sequence,
-                    data,
-                    timeout_height,
-                    source_port,
-                    source_channel,
-                    dest_port,
-                    dest_channel
-                );
-                info!("[{}] RecvPacket data: {:?}", chain_name, data);
-                // relay packet acknowledgement with this sequence number
-                let packet_data = Packet {
-                    sequence,
-                    timeout_height,
-                    source_port: source_port.clone(),
-                    source_channel,
-                    dest_port,
-                    dest_channel,
-                    data,
-                };
-                let acknowledgements = ibc::AcknowledgementsStore::<Runtime> {
-                    key: (source_port, source_channel, timeout_height.into()),
-                    _runtime: Default::default(),
-                };
-                let key = acknowledgements.key(&client.metadata())?;
-                let proof = client.read_proof(vec![key], Some(block_hash)).await?;
-                let datagram = Datagram::PacketAcknowledgement {
-                    packet: packet_data,
-                    acknowledgement,
-                    proof: StorageProof::new(proof.proof.into_iter().map(|b| b.0).collect()),
-                    proof_height: block_number,
-                };
-                tx.send(datagram).unwrap();
+            info!("reloading {}", config_path);
+            let reloaded = match read_config(config_path) {
+                Ok(config) => relayer.reload(&config).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = reloaded {
+                error!("failed to reload {}; error = {}", config_path, e);
             }
-            _ => {}
         }
-    }
-
-    Ok(())
+        relayer.shutdown().await
+    })
 }
@@ -0,0 +1,418 @@
+//! A chain kept in memory, to exercise the relaying deterministically without running nodes.
+use crate::chain::{Chain, EventStream, Header, HeaderStream, IbcEvent, StorageItem, Submission};
+use async_trait::async_trait;
+use codec::Encode;
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::{future, FutureExt, StreamExt};
+use pallet_ibc::{ChannelEnd, ClientState, ConnectionEnd, ConsensusState, Datagram};
+use sp_core::H256;
+use sp_finality_grandpa::AuthorityList;
+use sp_runtime::{
+    traits::{BlakeTwo256, Header as _},
+    Digest, Justification,
+};
+use sp_state_machine::{prove_read, InMemoryBackend};
+use sp_trie::StorageProof;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct Storage {
+    clients: BTreeMap<H256, ClientState>,
+    consensus_states: BTreeMap<(H256, u32), ConsensusState>,
+    connections: BTreeMap<H256, ConnectionEnd>,
+    channels: BTreeMap<(Vec<u8>, H256), ChannelEnd>,
+    packets: BTreeMap<(Vec<u8>, H256, u64), H256>,
+    acknowledgements: BTreeMap<(Vec<u8>, H256, u64), H256>,
+    authorities: AuthorityList,
+}
+
+impl Storage {
+    /// The trie of the provable items, keyed by their encoded `StorageItem`.
+    fn backend(&self) -> InMemoryBackend<BlakeTwo256> {
+        let mut trie = BTreeMap::new();
+        for ((identifier, height), consensus_state) in &self.consensus_states {
+            let item = StorageItem::ConsensusState(*identifier, *height);
+            trie.insert(item.encode(), consensus_state.encode());
+        }
+        for (identifier, connection_end) in &self.connections {
+            let item = StorageItem::Connection(*identifier);
+            trie.insert(item.encode(), connection_end.encode());
+        }
+        for ((port, channel), channel_end) in &self.channels {
+            let item = StorageItem::Channel(port.clone(), *channel);
+            trie.insert(item.encode(), channel_end.encode());
+        }
+        for ((port, channel, sequence), commitment) in &self.packets {
+            let item = StorageItem::Packet(port.clone(), *channel, *sequence);
+            trie.insert(item.encode(), commitment.encode());
+        }
+        for ((port, channel, sequence), acknowledgement) in &self.acknowledgements {
+            let item = StorageItem::Acknowledgement(port.clone(), *channel, *sequence);
+            trie.insert(item.encode(), acknowledgement.encode());
+        }
+        trie.insert(
+            StorageItem::GrandpaAuthorities.encode(),
+            self.authorities.encode(),
+        );
+        trie.into()
+    }
+}
+
+struct Block {
+    header: Header,
+    justification: Option<Justification>,
+    /// The state after the block.
+    storage: Storage,
+    events: Vec<IbcEvent>,
+}
+
+#[derive(Default)]
+struct State {
+    blocks: Vec<Block>,
+    /// The state of the next block.
+    storage: Storage,
+    /// The events of the next block.
+    events: Vec<IbcEvent>,
+    submitted: Vec<Datagram>,
+    subscribers: Vec<UnboundedSender<Header>>,
+    event_subscribers: Vec<UnboundedSender<(Header, Vec<IbcEvent>)>>,
+}
+
+/// A chain whose blocks are produced on demand and finalized right away.
+///
+/// Storage is changed and events are deposited for the next block, submitted datagrams are only
+/// recorded. Items are stored in a trie under their encoded `StorageItem`, so proofs are real
+/// proofs against the state root of their block.
+#[derive(Clone)]
+pub struct MemoryChain {
+    name: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl MemoryChain {
+    /// Creates a chain with a genesis block.
+    pub fn new(name: &str) -> Self {
+        let chain = Self {
+            name: name.to_string(),
+            state: Default::default(),
+        };
+        chain.produce_block();
+        chain
+    }
+
+    pub fn insert_client(&self, identifier: H256, client_state: ClientState) {
+        let mut state = self.state.lock().unwrap();
+        state.storage.clients.insert(identifier, client_state);
+    }
+
+    pub fn insert_consensus_state(
+        &self,
+        identifier: H256,
+        height: u32,
+        consensus_state: ConsensusState,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .storage
+            .consensus_states
+            .insert((identifier, height), consensus_state);
+    }
+
+    pub fn insert_connection(&self, identifier: H256, connection_end: ConnectionEnd) {
+        let mut state = self.state.lock().unwrap();
+        state.storage.connections.insert(identifier, connection_end);
+    }
+
+    pub fn insert_channel(&self, port: &[u8], channel: H256, channel_end: ChannelEnd) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .storage
+            .channels
+            .insert((port.to_vec(), channel), channel_end);
+    }
+
+    /// Stores the commitment of the packet sent on `port` and `channel` with `sequence`.
+    pub fn insert_packet(&self, port: &[u8], channel: H256, sequence: u64, commitment: H256) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .storage
+            .packets
+            .insert((port.to_vec(), channel, sequence), commitment);
+    }
+
+    /// Removes the commitment of a packet, as acknowledging or timing it out does.
+    pub fn remove_packet(&self, port: &[u8], channel: H256, sequence: u64) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .storage
+            .packets
+            .remove(&(port.to_vec(), channel, sequence));
+    }
+
+    pub fn insert_acknowledgement(
+        &self,
+        port: &[u8],
+        channel: H256,
+        sequence: u64,
+        acknowledgement: H256,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .storage
+            .acknowledgements
+            .insert((port.to_vec(), channel, sequence), acknowledgement);
+    }
+
+    pub fn set_authorities(&self, authorities: AuthorityList) {
+        self.state.lock().unwrap().storage.authorities = authorities;
+    }
+
+    pub fn deposit_event(&self, event: IbcEvent) {
+        self.state.lock().unwrap().events.push(event);
+    }
+
+    /// Produces and finalizes a block without a justification.
+    pub fn produce_block(&self) -> Header {
+        self.seal(None)
+    }
+
+    /// Produces and finalizes a block with a justification.
+    pub fn produce_justified_block(&self) -> Header {
+        self.seal(Some(b"justification".to_vec()))
+    }
+
+    /// Takes the datagrams submitted so far.
+    pub fn take_submitted(&self) -> Vec<Datagram> {
+        std::mem::take(&mut self.state.lock().unwrap().submitted)
+    }
+
+    fn seal(&self, justification: Option<Justification>) -> Header {
+        let mut state = self.state.lock().unwrap();
+        let number = state.blocks.len() as u32;
+        let parent_hash = state
+            .blocks
+            .last()
+            .map_or_else(H256::zero, |block| block.header.hash());
+        let state_root = *state.storage.backend().root();
+        let header = Header::new(
+            number,
+            H256::zero(),
+            state_root,
+            parent_hash,
+            Digest::default(),
+        );
+        let events = std::mem::take(&mut state.events);
+        state.event_subscribers.retain(|subscriber| {
+            subscriber
+                .unbounded_send((header.clone(), events.clone()))
+                .is_ok()
+        });
+        let block = Block {
+            header: header.clone(),
+            justification,
+            storage: state.storage.clone(),
+            events,
+        };
+        state.blocks.push(block);
+        state
+            .subscribers
+            .retain(|subscriber| subscriber.unbounded_send(header.clone()).is_ok());
+        header
+    }
+
+    /// Reads from the block `hash`, or from the last block.
+    fn read<T, F: FnOnce(&Block) -> T>(
+        &self,
+        hash: Option<H256>,
+        f: F,
+    ) -> Result<T, Box<dyn Error>> {
+        let state = self.state.lock().unwrap();
+        let block = match hash {
+            Some(hash) => state
+                .blocks
+                .iter()
+                .find(|block| block.header.hash() == hash)
+                .ok_or_else(|| format!("[{}] block {:?} not found", self.name, hash))?,
+            None => state.blocks.last().expect("genesis block exists; qed"),
+        };
+        Ok(f(block))
+    }
+}
+
+#[async_trait]
+impl Chain for MemoryChain {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn finalized_heads(&mut self) -> HeaderStream {
+        let (sender, receiver) = mpsc::unbounded();
+        self.state.lock().unwrap().subscribers.push(sender);
+        receiver.boxed()
+    }
+
+    async fn is_alive(&self) -> bool {
+        true
+    }
+
+    async fn reconnect(&mut self) {}
+
+    async fn finalized_height(&self) -> Result<u32, Box<dyn Error>> {
+        self.read(None, |block| block.header.number)
+    }
+
+    async fn block_hash(&self, height: u32) -> Result<Option<H256>, Box<dyn Error>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .blocks
+            .get(height as usize)
+            .map(|block| block.header.hash()))
+    }
+
+    async fn header(&self, height: u32) -> Result<Header, Box<dyn Error>> {
+        let state = self.state.lock().unwrap();
+        let block = state
+            .blocks
+            .get(height as usize)
+            .ok_or_else(|| format!("[{}] block #{} not found", self.name, height))?;
+        Ok(block.header.clone())
+    }
+
+    async fn justification(&self, hash: H256) -> Result<Option<Justification>, Box<dyn Error>> {
+        self.read(Some(hash), |block| block.justification.clone())
+    }
+
+    async fn authorities(&self, hash: H256) -> Result<AuthorityList, Box<dyn Error>> {
+        self.read(Some(hash), |block| block.storage.authorities.clone())
+    }
+
+    async fn client_state(
+        &self,
+        identifier: H256,
+        hash: Option<H256>,
+    ) -> Result<ClientState, Box<dyn Error>> {
+        self.read(hash, |block| {
+            block
+                .storage
+                .clients
+                .get(&identifier)
+                .cloned()
+                .unwrap_or_default()
+        })
+    }
+
+    async fn consensus_state(
+        &self,
+        identifier: H256,
+        height: u32,
+        hash: Option<H256>,
+    ) -> Result<Option<ConsensusState>, Box<dyn Error>> {
+        self.read(hash, |block| {
+            block
+                .storage
+                .consensus_states
+                .get(&(identifier, height))
+                .cloned()
+        })
+    }
+
+    async fn connection_end(
+        &self,
+        identifier: H256,
+        hash: Option<H256>,
+    ) -> Result<ConnectionEnd, Box<dyn Error>> {
+        self.read(hash, |block| {
+            block
+                .storage
+                .connections
+                .get(&identifier)
+                .cloned()
+                .unwrap_or_default()
+        })
+    }
+
+    async fn channel_end(
+        &self,
+        port: Vec<u8>,
+        channel: H256,
+        hash: Option<H256>,
+    ) -> Result<ChannelEnd, Box<dyn Error>> {
+        self.read(hash, |block| {
+            block
+                .storage
+                .channels
+                .get(&(port, channel))
+                .cloned()
+                .unwrap_or_default()
+        })
+    }
+
+    async fn packets(&self, hash: H256) -> Result<Vec<(Vec<u8>, H256, u64)>, Box<dyn Error>> {
+        self.read(Some(hash), |block| {
+            block.storage.packets.keys().cloned().collect()
+        })
+    }
+
+    async fn packet_commitment(
+        &self,
+        port: Vec<u8>,
+        channel: H256,
+        sequence: u64,
+        hash: H256,
+    ) -> Result<Option<H256>, Box<dyn Error>> {
+        self.read(Some(hash), |block| {
+            block
+                .storage
+                .packets
+                .get(&(port, channel, sequence))
+                .cloned()
+        })
+    }
+
+    async fn acknowledgement(
+        &self,
+        port: Vec<u8>,
+        channel: H256,
+        sequence: u64,
+        hash: H256,
+    ) -> Result<Option<H256>, Box<dyn Error>> {
+        self.read(Some(hash), |block| {
+            block
+                .storage
+                .acknowledgements
+                .get(&(port, channel, sequence))
+                .copied()
+        })
+    }
+
+    fn storage_key(&self, item: &StorageItem) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(item.encode())
+    }
+
+    async fn read_proof(
+        &self,
+        item: StorageItem,
+        hash: H256,
+    ) -> Result<StorageProof, Box<dyn Error>> {
+        let backend = self.read(Some(hash), |block| block.storage.backend())?;
+        let proof = prove_read(backend, &[item.encode()]).map_err(|e| e.to_string())?;
+        Ok(proof)
+    }
+
+    async fn ibc_events(&self, hash: H256) -> Result<Vec<IbcEvent>, Box<dyn Error>> {
+        self.read(Some(hash), |block| block.events.clone())
+    }
+
+    async fn unfinalized_ibc_events(&self) -> EventStream {
+        let (sender, receiver) = mpsc::unbounded();
+        self.state.lock().unwrap().event_subscribers.push(sender);
+        receiver.boxed()
+    }
+
+    fn submit(&self, _path: &str, datagram: Datagram) -> Submission {
+        self.state.lock().unwrap().submitted.push(datagram);
+        future::ready(Ok(())).boxed()
+    }
+}
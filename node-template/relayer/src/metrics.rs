@@ -0,0 +1,108 @@
+//! Prometheus metrics of the relayer.
+use log::{error, info};
+use prometheus_endpoint::{
+    prometheus::{GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts},
+    register, PrometheusError, Registry,
+};
+use std::net::SocketAddr;
+
+/// All metrics of the relayer, labelled by relay path (`from:to`) or by chain.
+///
+/// Metrics are always collected, they are only served when a listen address is configured.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// Datagrams included in a block, by path and datagram variant.
+    pub datagrams_submitted: IntCounterVec,
+    /// Datagrams rejected by the runtime or given up on, by path and datagram variant.
+    pub datagrams_failed: IntCounterVec,
+    /// Time from submitting a datagram to its inclusion, by path and datagram variant.
+    pub submission_latency: HistogramVec,
+    /// Finalized height of the source chain minus the latest height of its client on the
+    /// counterparty.
+    pub client_lag: IntGaugeVec,
+    /// Packets sent on the path that are neither received nor timed out.
+    pub pending_packets: IntGaugeVec,
+    /// Next nonce of the signer, by chain.
+    pub signer_nonce: IntGaugeVec,
+    /// Free balance of the signer, by chain.
+    pub signer_balance: GaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, PrometheusError> {
+        let registry = Registry::new_custom(Some("ibc_relayer".into()), None)?;
+        Ok(Self {
+            datagrams_submitted: register(
+                IntCounterVec::new(
+                    Opts::new(
+                        "datagrams_submitted_total",
+                        "Datagrams included in a block",
+                    ),
+                    &["path", "datagram"],
+                )?,
+                &registry,
+            )?,
+            datagrams_failed: register(
+                IntCounterVec::new(
+                    Opts::new(
+                        "datagrams_failed_total",
+                        "Datagrams rejected by the runtime or given up on",
+                    ),
+                    &["path", "datagram"],
+                )?,
+                &registry,
+            )?,
+            submission_latency: register(
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "submission_latency_seconds",
+                        "Time from submitting a datagram to its inclusion in a block",
+                    ),
+                    &["path", "datagram"],
+                )?,
+                &registry,
+            )?,
+            client_lag: register(
+                IntGaugeVec::new(
+                    Opts::new(
+                        "client_lag_blocks",
+                        "Finalized height of the source chain minus the latest height of its client on the counterparty",
+                    ),
+                    &["path"],
+                )?,
+                &registry,
+            )?,
+            pending_packets: register(
+                IntGaugeVec::new(
+                    Opts::new(
+                        "pending_packets",
+                        "Packets neither received nor timed out",
+                    ),
+                    &["path"],
+                )?,
+                &registry,
+            )?,
+            signer_nonce: register(
+                IntGaugeVec::new(Opts::new("signer_nonce", "Next nonce of the signer"), &["chain"])?,
+                &registry,
+            )?,
+            signer_balance: register(
+                GaugeVec::new(
+                    Opts::new("signer_balance", "Free balance of the signer"),
+                    &["chain"],
+                )?,
+                &registry,
+            )?,
+            registry,
+        })
+    }
+
+    /// Serves the metrics on `address` until the listener fails.
+    pub async fn serve(self, address: SocketAddr) {
+        info!("serving metrics on {}", address);
+        if let Err(e) = prometheus_endpoint::init_prometheus(address, self.registry).await {
+            error!("failed to serve metrics on {}; error = {}", address, e);
+        }
+    }
+}
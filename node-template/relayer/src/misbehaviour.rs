@@ -0,0 +1,113 @@
+//! Watching clients for misbehaviour of the chains they track.
+//!
+//! A client holds the state root of every header it was updated to. If the tracked chain has
+//! finalized a different block at the height of such a header, either the chain finalized two
+//! conflicting blocks or the client was fed a forged header; both are evidence the client must
+//! be frozen on.
+use crate::chain::{Chain, HeaderStream};
+use crate::client_update;
+use crate::relay::RelayPath;
+use futures::StreamExt;
+use log::{error, info};
+use pallet_ibc::Datagram;
+use std::cmp;
+use std::error::Error;
+
+/// Watches the client of the source chain of `path` held by its destination chain for as long
+/// as the relayer runs, checking it at every finalized block of the source chain and submitting
+/// the evidence of any misbehaviour to the destination chain.
+pub async fn monitor<C: Chain>(path: RelayPath<C>, mut block_headers: HeaderStream) {
+    let mut checked_height = None;
+    while block_headers.next().await.is_some() {
+        let previously_checked = checked_height;
+        let evidence = match find_misbehaviour(&path, &mut checked_height).await {
+            Ok(evidence) => evidence,
+            Err(e) => {
+                error!(
+                    "[{}] failed to check client {:?}; error = {}",
+                    path.to.name(),
+                    path.to_client_identifier,
+                    e
+                );
+                None
+            }
+        };
+        if let Some(datagram) = evidence {
+            let submitted = path.to.submit(&path.name(), datagram).await.is_ok();
+            if submitted {
+                info!(
+                    "[{}] submitted misbehaviour of {} for client {:?}",
+                    path.to.name(),
+                    path.from.name(),
+                    path.to_client_identifier
+                );
+            } else {
+                error!(
+                    "[{}] failed to submit misbehaviour of {} for client {:?}",
+                    path.to.name(),
+                    path.from.name(),
+                    path.to_client_identifier
+                );
+                // Tries again at the next block.
+                checked_height = previously_checked;
+            }
+        }
+    }
+}
+
+/// Compares the consensus states the destination chain of `path` holds for its client of the
+/// source chain with the finalized blocks of the source chain at their heights.
+///
+/// Every height from the one after `checked_height` up to the latest height of the client is
+/// checked, so that no header is missed when the client was updated to several at once; the first
+/// check only looks at the latest height. Returns the datagram freezing the client at the first
+/// conflict, proven by the justified header of the source chain. Heights the source chain has not
+/// finalized yet are left for later.
+pub async fn find_misbehaviour<C: Chain>(
+    path: &RelayPath<C>,
+    checked_height: &mut Option<u32>,
+) -> Result<Option<Datagram>, Box<dyn Error>> {
+    let identifier = path.to_client_identifier;
+    let latest_height = path.to.client_state(identifier, None).await?.latest_height;
+    let finalized_height = path.from.finalized_height().await?;
+    let from_height = checked_height.map_or(latest_height, |height| height + 1);
+    for height in from_height..=cmp::min(latest_height, finalized_height) {
+        let consensus_state = path.to.consensus_state(identifier, height, None).await?;
+        *checked_height = Some(height);
+        let consensus_state = match consensus_state {
+            Some(consensus_state) => consensus_state,
+            None => continue,
+        };
+        let header = path.from.header(height).await?;
+        if header.state_root == consensus_state.commitment_root {
+            continue;
+        }
+        error!(
+            "ALERT: [{}] client {:?} holds the state root {:?} at #{} where {} finalized {:?}",
+            path.to.name(),
+            identifier,
+            consensus_state.commitment_root,
+            height,
+            path.from.name(),
+            header.state_root
+        );
+        return match client_update::justified_header(&path.from, height).await? {
+            Some(evidence) => Ok(Some(Datagram::ClientMisbehaviour {
+                identifier,
+                evidence,
+            })),
+            None => {
+                error!(
+                    "ALERT: [{}] block #{} has no justification to prove the misbehaviour with, \
+                     client {:?} on {} is left unfrozen",
+                    path.from.name(),
+                    height,
+                    identifier,
+                    path.to.name()
+                );
+                Ok(None)
+            }
+        };
+    }
+    Ok(None)
+}
@@ -0,0 +1,138 @@
+//! Packets that have been sent on one chain but neither received nor timed out on the other.
+use pallet_ibc::Packet;
+use sp_core::H256;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+type PacketKey = (Vec<u8>, H256, u64);
+
+/// Pending packets of every path, keyed by the names of the sending and the receiving chain.
+///
+/// A packet is added by the path relaying it and removed by the opposite path, once it sees the
+/// packet received or sees its commitment gone from the sending chain after relaying its timeout,
+/// so timeouts are only relayed for bidirectional paths and only their packets are tracked.
+#[derive(Debug, Default)]
+pub struct PendingPackets {
+    paths: HashMap<(String, String), BTreeMap<PacketKey, Pending>>,
+    /// The paths whose packets are tracked, by the names of their chains, every path if `None`.
+    tracked: Option<HashSet<(String, String)>>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    packet: Packet,
+    /// Whether the timeout of the packet is being submitted.
+    timing_out: bool,
+}
+
+fn key(packet: &Packet) -> PacketKey {
+    (
+        packet.source_port.clone(),
+        packet.source_channel,
+        packet.sequence,
+    )
+}
+
+impl PendingPackets {
+    /// Tracks the packets of the paths of `tracked` only, forgetting those of the others.
+    pub fn track(&mut self, tracked: HashSet<(String, String)>) {
+        self.paths.retain(|path, _| tracked.contains(path));
+        self.tracked = Some(tracked);
+    }
+
+    /// Whether the packets sent on `from` to `to` are tracked.
+    pub fn is_tracked(&self, from: &str, to: &str) -> bool {
+        self.tracked.as_ref().map_or(true, |tracked| {
+            tracked.contains(&(from.to_string(), to.to_string()))
+        })
+    }
+
+    /// Records `packet` as sent on `from` to `to`, if the packets of that path are tracked.
+    pub fn insert(&mut self, from: &str, to: &str, packet: Packet) {
+        if !self.is_tracked(from, to) {
+            return;
+        }
+        self.paths
+            .entry((from.to_string(), to.to_string()))
+            .or_default()
+            .entry(key(&packet))
+            .or_insert(Pending {
+                packet,
+                timing_out: false,
+            });
+    }
+
+    /// Forgets a packet sent on `from` to `to`.
+    pub fn remove(
+        &mut self,
+        from: &str,
+        to: &str,
+        source_port: &[u8],
+        source_channel: H256,
+        sequence: u64,
+    ) -> Option<Packet> {
+        self.paths
+            .get_mut(&(from.to_string(), to.to_string()))?
+            .remove(&(source_port.to_vec(), source_channel, sequence))
+            .map(|pending| pending.packet)
+    }
+
+    /// Records whether the timeout of `packet`, sent on `from` to `to`, is being submitted.
+    pub fn set_timing_out(&mut self, from: &str, to: &str, packet: &Packet, timing_out: bool) {
+        if let Some(pending) = self
+            .paths
+            .get_mut(&(from.to_string(), to.to_string()))
+            .and_then(|packets| packets.get_mut(&key(packet)))
+        {
+            pending.timing_out = timing_out;
+        }
+    }
+
+    /// Whether the timeout of `packet`, sent on `from` to `to`, is being submitted.
+    pub fn is_timing_out(&self, from: &str, to: &str, packet: &Packet) -> bool {
+        self.paths
+            .get(&(from.to_string(), to.to_string()))
+            .and_then(|packets| packets.get(&key(packet)))
+            .map_or(false, |pending| pending.timing_out)
+    }
+
+    /// Returns the number of packets sent on `from` to `to`.
+    pub fn len(&self, from: &str, to: &str) -> usize {
+        self.paths
+            .get(&(from.to_string(), to.to_string()))
+            .map_or(0, |packets| packets.len())
+    }
+
+    /// Returns the sequences of the packets sent on `from` to `to`, by source port and channel.
+    pub fn by_channel(&self, from: &str, to: &str) -> BTreeMap<(Vec<u8>, H256), Vec<u64>> {
+        let mut channels: BTreeMap<_, Vec<u64>> = BTreeMap::new();
+        if let Some(packets) = self.paths.get(&(from.to_string(), to.to_string())) {
+            for (port, channel, sequence) in packets.keys() {
+                channels
+                    .entry((port.clone(), *channel))
+                    .or_default()
+                    .push(*sequence);
+            }
+        }
+        channels
+    }
+
+    /// Returns the packets sent on `from` that `to` can no longer receive at `height`.
+    pub fn timed_out(&self, from: &str, to: &str, height: u32) -> Vec<Packet> {
+        self.paths
+            .get(&(from.to_string(), to.to_string()))
+            .map(|packets| {
+                packets
+                    .values()
+                    .map(|pending| &pending.packet)
+                    .filter(|packet| is_timed_out(packet, height))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Whether the receiving chain refuses `packet` at `height`; a zero timeout height never expires.
+pub fn is_timed_out(packet: &Packet, height: u32) -> bool {
+    packet.timeout_height != 0 && height >= packet.timeout_height
+}
@@ -0,0 +1,78 @@
+//! Local verification of the storage proofs put into datagrams.
+//!
+//! A proof the counterparty rejects costs a failed extrinsic and says little about what went
+//! wrong, so every proof is checked against the state root of its block before it is relayed.
+use crate::chain::{Chain, Header, StorageItem};
+use log::error;
+use sp_core::H256;
+use sp_runtime::traits::{BlakeTwo256, Header as _};
+use sp_state_machine::read_proof_check;
+use sp_trie::StorageProof;
+use std::error::Error;
+
+/// What a proof must show about its storage item.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expected {
+    /// The item holds this encoded value.
+    Value(Vec<u8>),
+    /// The item holds some value.
+    Exists,
+    /// The item holds no value.
+    Absent,
+}
+
+/// Checks that `proof` shows `expected` for the storage `key` in the state with root
+/// `state_root`.
+pub fn verify(
+    state_root: H256,
+    proof: StorageProof,
+    key: &[u8],
+    expected: &Expected,
+) -> Result<(), String> {
+    let mut values = read_proof_check::<BlakeTwo256, _>(state_root, proof, &[key])
+        .map_err(|e| format!("proof does not match the state root: {}", e))?;
+    let value = values.remove(key).flatten();
+    match (expected, value) {
+        (Expected::Value(expected), Some(value)) if *expected == value => Ok(()),
+        (Expected::Value(expected), Some(value)) => Err(format!(
+            "proof shows the value 0x{} instead of 0x{}",
+            hex::encode(value),
+            hex::encode(expected)
+        )),
+        (Expected::Exists, Some(_)) | (Expected::Absent, None) => Ok(()),
+        (Expected::Value(_), None) | (Expected::Exists, None) => {
+            Err("proof shows that the key has no value".to_string())
+        }
+        (Expected::Absent, Some(value)) => Err(format!(
+            "proof shows the value 0x{} where none was expected",
+            hex::encode(value)
+        )),
+    }
+}
+
+/// Reads a proof of `item` in the state of the block `header` of `chain` and verifies it.
+///
+/// Returns `None` when the proof does not show `expected`, after logging why, so that the
+/// datagram that would carry it is not relayed.
+pub async fn read_verified_proof<C: Chain>(
+    chain: &C,
+    item: StorageItem,
+    expected: Expected,
+    header: &Header,
+) -> Result<Option<StorageProof>, Box<dyn Error>> {
+    let key = chain.storage_key(&item)?;
+    let proof = chain.read_proof(item.clone(), header.hash()).await?;
+    match verify(header.state_root, proof.clone(), &key, &expected) {
+        Ok(()) => Ok(Some(proof)),
+        Err(e) => {
+            error!(
+                "[{}] refusing to relay the proof of {:?} at #{}: {}",
+                chain.name(),
+                item,
+                header.number,
+                e
+            );
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,982 @@
+//! Relaying the finalized blocks of one chain to another.
+use crate::chain::{Chain, Header, HeaderStream, IbcEvent, StorageItem};
+use crate::checkpoint::CheckpointStore;
+use crate::client_update;
+use crate::connection::FINALITY_TIMEOUT;
+use crate::datagram;
+use crate::filter::PathFilter;
+use crate::handshake::{HandshakeEnd, InFlightHandshakes};
+use crate::metrics::Metrics;
+use crate::packets::{self, PendingPackets};
+use crate::proof::{read_verified_proof, Expected};
+use codec::Encode;
+use futures::channel::{mpsc::UnboundedSender, oneshot};
+use futures::future::{self, Either, FutureExt, Shared};
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use pallet_ibc::{ChannelState, ConnectionState, Datagram, Packet, RawEvent};
+use sp_core::H256;
+use sp_runtime::traits::Header as _;
+use sp_trie::StorageProof;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long to wait before subscribing again to events that ended.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+#[cfg(test)]
+mod tests;
+
+/// One direction of a configured relay path.
+#[derive(Clone)]
+pub struct RelayPath<C> {
+    pub from: C,
+    /// The client on `from` that tracks `to`.
+    pub from_client_identifier: H256,
+    pub to: C,
+    /// The client on `to` that tracks `from`.
+    pub to_client_identifier: H256,
+    pub filter: PathFilter,
+    /// Packets awaiting receipt or timeout, shared by all paths.
+    pub pending_packets: Arc<Mutex<PendingPackets>>,
+    /// Handshake steps awaiting their effect, shared by all paths.
+    pub handshakes: Arc<Mutex<InFlightHandshakes>>,
+    pub metrics: Metrics,
+}
+
+impl<C: Chain> RelayPath<C> {
+    /// The name of the path in logs and metrics.
+    pub fn name(&self) -> String {
+        format!("{}:{}", self.from.name(), self.to.name())
+    }
+
+    /// Sends the handshake step `datagram` to the counterparty, unless it is already in flight.
+    fn send_handshake(&self, tx: &UnboundedSender<Datagram>, datagram: Datagram) {
+        if self
+            .handshakes
+            .lock()
+            .unwrap()
+            .begin(self.to.name(), &datagram)
+        {
+            tx.unbounded_send(datagram).unwrap();
+        } else {
+            debug!(
+                "[{}] {} already in flight",
+                self.from.name(),
+                datagram::kind(&datagram)
+            );
+        }
+    }
+}
+
+/// The state of a followed path shared with the admin interface, which can also pause it, and
+/// with the relayer, which can stop it.
+pub struct PathControl {
+    paused: AtomicBool,
+    /// The last block of the source chain relayed.
+    last_height: AtomicU32,
+    /// The last block of the source chain finalized, as far as the path knows.
+    target_height: AtomicU32,
+    stop: Mutex<Option<oneshot::Sender<()>>>,
+    stopped: Shared<oneshot::Receiver<()>>,
+}
+
+impl Default for PathControl {
+    fn default() -> Self {
+        let (stop, stopped) = oneshot::channel();
+        Self {
+            paused: AtomicBool::new(false),
+            last_height: AtomicU32::new(0),
+            target_height: AtomicU32::new(0),
+            stop: Mutex::new(Some(stop)),
+            stopped: stopped.shared(),
+        }
+    }
+}
+
+impl PathControl {
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Pauses or resumes relaying; a resumed path catches up with the blocks finalized meanwhile.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    /// Stops the path for good once the block being relayed, if any, is done.
+    pub fn stop(&self) {
+        if let Some(stop) = self.stop.lock().unwrap().take() {
+            let _ = stop.send(());
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.clone().now_or_never().is_some()
+    }
+
+    /// Resolves once the path is stopped.
+    pub async fn stopped(&self) {
+        let _ = self.stopped.clone().await;
+    }
+
+    pub fn last_height(&self) -> u32 {
+        self.last_height.load(Ordering::SeqCst)
+    }
+
+    pub fn target_height(&self) -> u32 {
+        self.target_height.load(Ordering::SeqCst)
+    }
+
+    fn set_heights(&self, last_height: u32, target_height: u32) {
+        self.last_height.store(last_height, Ordering::SeqCst);
+        self.target_height.store(target_height, Ordering::SeqCst);
+    }
+}
+
+/// Relays the finalized blocks of the source chain of `path` until `control` is stopped,
+/// starting with the ones after `last_height` up to `target_height`.
+///
+/// Whenever either chain goes away its connection is rebuilt, and relaying resumes right after
+/// the last block relayed. While `control` is paused, finalized blocks are only counted. Once
+/// stopped, `tx` is dropped so that the datagrams already sent can be drained.
+pub async fn follow<C: Chain>(
+    mut path: RelayPath<C>,
+    mut block_headers: HeaderStream,
+    tx: UnboundedSender<Datagram>,
+    checkpoints: Arc<Mutex<CheckpointStore>>,
+    control: Arc<PathControl>,
+    mut last_height: u32,
+    mut target_height: u32,
+) {
+    loop {
+        control.set_heights(last_height, target_height);
+        let failed = if control.is_paused() {
+            false
+        } else {
+            let result = relay_blocks(
+                &path,
+                tx.clone(),
+                &checkpoints,
+                &control,
+                &mut last_height,
+                target_height,
+            )
+            .await;
+            match result {
+                Ok(()) => false,
+                Err(e) => {
+                    error!("[{}] failed to relay; error = {}", path.from.name(), e);
+                    true
+                }
+            }
+        };
+        if control.is_stopped() {
+            info!("[{}] stopped at #{}", path.name(), last_height);
+            return;
+        }
+        if failed && !path.to.is_alive().await {
+            warn!("[{}] lost connection, reconnecting", path.to.name());
+            path.to.reconnect().await;
+        }
+
+        let next_header = async_std::future::timeout(FINALITY_TIMEOUT, block_headers.next());
+        let next_header =
+            match future::select(Box::pin(next_header), Box::pin(control.stopped())).await {
+                Either::Left((next_header, _)) => next_header,
+                Either::Right(_) => continue,
+            };
+        match next_header {
+            Ok(Some(block_header)) => target_height = block_header.number,
+            Ok(None) => {
+                warn!(
+                    "[{}] finalized heads ended, resubscribing",
+                    path.from.name()
+                );
+                block_headers = path.from.finalized_heads().await;
+            }
+            Err(_) => {
+                if !path.from.is_alive().await {
+                    warn!("[{}] lost connection, reconnecting", path.from.name());
+                    path.from.reconnect().await;
+                    block_headers = path.from.finalized_heads().await;
+                }
+            }
+        }
+    }
+}
+
+/// Watches the IBC events of the blocks of the source chain of `path` before they are finalized,
+/// so that packets are known to be pending as soon as they are sent.
+///
+/// Nothing is relayed from these blocks, which may still be reverted; `follow` relays them once
+/// they are finalized.
+pub async fn watch_unfinalized<C: Chain>(path: RelayPath<C>, control: Arc<PathControl>) {
+    let watch = async {
+        loop {
+            let mut blocks = path.from.unfinalized_ibc_events().await;
+            while let Some((header, events)) = blocks.next().await {
+                record_unfinalized_events(&path, &header, events);
+            }
+            warn!(
+                "[{}] unfinalized events ended, resubscribing",
+                path.from.name()
+            );
+            async_std::task::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    };
+    future::select(Box::pin(watch), Box::pin(control.stopped())).await;
+}
+
+/// Records the packets sent in the unfinalized block `header` of the source chain of `path` as
+/// pending.
+pub fn record_unfinalized_events<C: Chain>(
+    path: &RelayPath<C>,
+    header: &Header,
+    events: Vec<IbcEvent>,
+) {
+    for event in events {
+        if let RawEvent::SendPacket(
+            sequence,
+            data,
+            timeout_height,
+            source_port,
+            source_channel,
+            dest_port,
+            dest_channel,
+        ) = event
+        {
+            if !path.filter.allows_channel(&source_port, source_channel) {
+                continue;
+            }
+            info!(
+                "[{}] SendPacket {} in unfinalized block #{}",
+                path.from.name(),
+                sequence,
+                header.number
+            );
+            let packet = Packet {
+                sequence,
+                timeout_height,
+                source_port,
+                source_channel,
+                dest_port,
+                dest_channel,
+                data,
+            };
+            path.pending_packets
+                .lock()
+                .unwrap()
+                .insert(path.from.name(), path.to.name(), packet);
+        }
+    }
+}
+
+/// Records as pending the packets sent by the source chain of `path` whose commitment is still
+/// stored at `height` and that the destination chain has not received, so that the packets the
+/// relayer was waiting on when it stopped are still timed out.
+///
+/// Their data comes from the `SendPacket` events of the blocks up to `height`, read backwards
+/// until every packet is found, at most `depth` blocks. Returns how many packets were recorded.
+pub async fn restore_pending_packets<C: Chain>(
+    path: &RelayPath<C>,
+    height: u32,
+    depth: u32,
+) -> Result<usize, Box<dyn Error>> {
+    if !path
+        .pending_packets
+        .lock()
+        .unwrap()
+        .is_tracked(path.from.name(), path.to.name())
+    {
+        return Ok(0);
+    }
+    let hash = path
+        .from
+        .block_hash(height)
+        .await?
+        .ok_or_else(|| format!("block #{} not found", height))?;
+    let counterparty_hash = path
+        .to
+        .block_hash(path.to.finalized_height().await?)
+        .await?
+        .ok_or("finalized block of the counterparty not found")?;
+    let mut missing = BTreeSet::new();
+    for (port, channel, sequence) in path.from.packets(hash).await? {
+        if !path.filter.allows_channel(&port, channel) {
+            continue;
+        }
+        let received = path
+            .to
+            .acknowledgement(port.clone(), channel, sequence, counterparty_hash)
+            .await?
+            .is_some();
+        if !received {
+            missing.insert((port, channel, sequence));
+        }
+    }
+    let mut restored = 0;
+    let lowest = height.saturating_sub(depth).max(1);
+    let mut height = height;
+    while !missing.is_empty() && height >= lowest {
+        let hash = path
+            .from
+            .block_hash(height)
+            .await?
+            .ok_or_else(|| format!("block #{} not found", height))?;
+        for event in path.from.ibc_events(hash).await? {
+            if let RawEvent::SendPacket(
+                sequence,
+                data,
+                timeout_height,
+                source_port,
+                source_channel,
+                dest_port,
+                dest_channel,
+            ) = event
+            {
+                if missing.remove(&(source_port.clone(), source_channel, sequence)) {
+                    let packet = Packet {
+                        sequence,
+                        timeout_height,
+                        source_port,
+                        source_channel,
+                        dest_port,
+                        dest_channel,
+                        data,
+                    };
+                    path.pending_packets.lock().unwrap().insert(
+                        path.from.name(),
+                        path.to.name(),
+                        packet,
+                    );
+                    restored += 1;
+                }
+            }
+        }
+        height -= 1;
+    }
+    if !missing.is_empty() {
+        warn!(
+            "[{}] no SendPacket event found down to #{} for packet commitment(s) {:?}",
+            path.from.name(),
+            lowest,
+            missing
+        );
+    }
+    Ok(restored)
+}
+
+/// Relays every finalized block of the source chain after `last_height` up to and including
+/// `target_height`, persisting the checkpoint of the path after each block.
+///
+/// Stops at the first block that fails, leaving `last_height` at the block before it so that the
+/// next call retries it instead of skipping it, and after the block being relayed once `control`
+/// is stopped.
+pub async fn relay_blocks<C: Chain>(
+    path: &RelayPath<C>,
+    tx: UnboundedSender<Datagram>,
+    checkpoints: &Mutex<CheckpointStore>,
+    control: &PathControl,
+    last_height: &mut u32,
+    target_height: u32,
+) -> Result<(), Box<dyn Error>> {
+    while *last_height < target_height && !control.is_stopped() {
+        let height = *last_height + 1;
+        let block_header = path.from.header(height).await?;
+        relay(path, tx.clone(), block_header).await?;
+        checkpoints
+            .lock()
+            .unwrap()
+            .set(path.from.name(), path.to.name(), height)?;
+        *last_height = height;
+        control.set_heights(height, target_height);
+    }
+    Ok(())
+}
+
+/// Sends the datagrams that carry the state of the block `block_header` of the source chain of
+/// `path` over to its counterparty.
+pub async fn relay<C: Chain>(
+    path: &RelayPath<C>,
+    tx: UnboundedSender<Datagram>,
+    block_header: Header,
+) -> Result<(), Box<dyn Error>> {
+    let chain_name = path.from.name();
+    let chain = &path.from;
+    let client_identifier = path.from_client_identifier;
+    let counterparty = &path.to;
+    let counterparty_client_identifier = path.to_client_identifier;
+
+    let block_number = block_header.number;
+    let state_root = block_header.state_root;
+    let block_hash = block_header.hash();
+    debug!("[{}] block_number: {}", chain_name, block_number);
+    debug!("[{}] state_root: {:?}", chain_name, state_root);
+    debug!("[{}] block_hash: {:?}", chain_name, block_hash);
+    let client_state = chain
+        .client_state(client_identifier, Some(block_hash))
+        .await?;
+
+    let counterparty_block_hash = counterparty.block_hash(client_state.latest_height).await?;
+    info!(
+        "[{}] client latest height: {}",
+        chain_name, client_state.latest_height
+    );
+    let counterparty_client_state = counterparty
+        .client_state(counterparty_client_identifier, None)
+        .await?;
+    // Measured from the finalized head, as the block relayed may lag behind it while catching up.
+    let finalized_height = chain.finalized_height().await?;
+    path.metrics
+        .client_lag
+        .with_label_values(&[&path.name()])
+        .set(i64::from(finalized_height) - i64::from(counterparty_client_state.latest_height));
+    for datagram in client_update::client_updates(
+        chain,
+        counterparty_client_identifier,
+        counterparty_client_state.latest_height,
+        block_number,
+    )
+    .await?
+    {
+        tx.unbounded_send(datagram).unwrap();
+    }
+    if !client_state.connections.is_empty() {
+        info!(
+            "[{}] connections: {:?}",
+            chain_name, client_state.connections
+        );
+    }
+    for connection in client_state.connections.iter() {
+        if !path.filter.allows_connection(*connection) {
+            continue;
+        }
+        let connection_end = chain.connection_end(*connection, Some(block_hash)).await?;
+        debug!("[{}] connection_end: {:#?}", chain_name, connection_end);
+        let remote_connection_end = counterparty
+            .connection_end(
+                connection_end.counterparty_connection_identifier,
+                counterparty_block_hash,
+            )
+            .await?;
+        debug!(
+            "[{}] remote_connection_end: {:#?}",
+            chain_name, remote_connection_end
+        );
+        info!(
+            "[{}] connection state: {:?}, counterparty connection state: {:?}",
+            chain_name, connection_end.state, remote_connection_end.state
+        );
+        // TODO: remote_connection_end == None ??
+        if connection_end.state == ConnectionState::Init
+            && remote_connection_end.state == ConnectionState::None
+        {
+            let (consensus_height, proof_consensus) = match consensus_state_proof(
+                path,
+                client_state.latest_height,
+                &block_header,
+                "ConnOpenTry",
+            )
+            .await?
+            {
+                Some(proof) => proof,
+                None => continue,
+            };
+            let proof_init = match read_verified_proof(
+                chain,
+                StorageItem::Connection(*connection),
+                Expected::Value(connection_end.encode()),
+                &block_header,
+            )
+            .await?
+            {
+                Some(proof) => proof,
+                None => continue,
+            };
+            let datagram = Datagram::ConnOpenTry {
+                desired_identifier: connection_end.counterparty_connection_identifier,
+                counterparty_connection_identifier: *connection,
+                counterparty_client_identifier: client_identifier,
+                client_identifier: counterparty_client_identifier,
+                version: vec![],
+                counterparty_version: vec![],
+                proof_init,
+                proof_consensus,
+                proof_height: block_number,
+                consensus_height,
+            };
+            path.send_handshake(&tx, datagram);
+        } else if connection_end.state == ConnectionState::TryOpen
+            && remote_connection_end.state == ConnectionState::Init
+        {
+            let proof_try = match read_verified_proof(
+                chain,
+                StorageItem::Connection(*connection),
+                Expected::Value(connection_end.encode()),
+                &block_header,
+            )
+            .await?
+            {
+                Some(proof) => proof,
+                None => continue,
+            };
+            let (consensus_height, proof_consensus) = match consensus_state_proof(
+                path,
+                client_state.latest_height,
+                &block_header,
+                "ConnOpenAck",
+            )
+            .await?
+            {
+                Some(proof) => proof,
+                None => continue,
+            };
+            let datagram = Datagram::ConnOpenAck {
+                identifier: connection_end.counterparty_connection_identifier,
+                version: vec![],
+                proof_try,
+                proof_consensus,
+                proof_height: block_number,
+                consensus_height,
+            };
+            path.send_handshake(&tx, datagram);
+        } else if connection_end.state == ConnectionState::Open
+            && remote_connection_end.state == ConnectionState::TryOpen
+        {
+            let proof_ack = match read_verified_proof(
+                chain,
+                StorageItem::Connection(*connection),
+                Expected::Value(connection_end.encode()),
+                &block_header,
+            )
+            .await?
+            {
+                Some(proof) => proof,
+                None => continue,
+            };
+            let datagram = Datagram::ConnOpenConfirm {
+                identifier: connection_end.counterparty_connection_identifier,
+                proof_ack,
+                proof_height: block_number,
+            };
+            path.send_handshake(&tx, datagram);
+        } else {
+            path.handshakes.lock().unwrap().settle(
+                counterparty.name(),
+                HandshakeEnd::Connection(connection_end.counterparty_connection_identifier),
+            );
+        }
+    }
+    if !client_state.channels.is_empty() {
+        info!("[{}] channels: {:?}", chain_name, client_state.channels);
+    }
+    for channel in client_state.channels.iter() {
+        if !path.filter.allows_channel(&channel.0, channel.1) {
+            continue;
+        }
+        let channel_end = chain
+            .channel_end(channel.0.clone(), channel.1, Some(block_hash))
+            .await?;
+        if !channel_end
+            .connection_hops
+            .iter()
+            .all(|hop| path.filter.allows_connection(*hop))
+        {
+            continue;
+        }
+
+        debug!("[{}] channel_end: {:#?}", chain_name, channel_end);
+        let remote_channel_end = counterparty
+            .channel_end(
+                channel_end.counterparty_port_identifier.clone(),
+                channel_end.counterparty_channel_identifier,
+                counterparty_block_hash,
+            )
+            .await?;
+        debug!(
+            "[{}] remote_channel_end: {:#?}",
+            chain_name, remote_channel_end
+        );
+        info!(
+            "[{}] channle state: {:?}, counterparty channel state: {:?}",
+            chain_name, channel_end.state, remote_channel_end.state
+        );
+        if channel_end.state == ChannelState::Init && remote_channel_end.state == ChannelState::None
+        {
+            let connection_end = chain
+                .connection_end(channel_end.connection_hops[0], Some(block_hash))
+                .await?;
+            let proof_init = match read_verified_proof(
+                chain,
+                StorageItem::Channel(channel.0.clone(), channel.1),
+                Expected::Value(channel_end.encode()),
+                &block_header,
+            )
+            .await?
+            {
+                Some(proof) => proof,
+                None => continue,
+            };
+            let datagram = Datagram::ChanOpenTry {
+                order: channel_end.ordering,
+                // connection_hops: channel_end.connection_hops.into_iter().rev().collect(), // ??
+                connection_hops: vec![connection_end.counterparty_connection_identifier],
+                port_identifier: channel_end.counterparty_port_identifier,
+                channel_identifier: channel_end.counterparty_channel_identifier,
+                counterparty_port_identifier: channel.0.clone(),
+                counterparty_channel_identifier: channel.1,
+                version: channel_end.version.clone(),
+                counterparty_version: channel_end.version,
+                proof_init,
+                proof_height: block_number,
+            };
+            path.send_handshake(&tx, datagram);
+        } else if channel_end.state == ChannelState::TryOpen
+            && remote_channel_end.state == ChannelState::Init
+        {
+            let proof_try = match read_verified_proof(
+                chain,
+                StorageItem::Channel(channel.0.clone(), channel.1),
+                Expected::Value(channel_end.encode()),
+                &block_header,
+            )
+            .await?
+            {
+                Some(proof) => proof,
+                None => continue,
+            };
+            let datagram = Datagram::ChanOpenAck {
+                port_identifier: channel_end.counterparty_port_identifier,
+                channel_identifier: channel_end.counterparty_channel_identifier,
+                version: remote_channel_end.version,
+                proof_try,
+                proof_height: block_number,
+            };
+            path.send_handshake(&tx, datagram);
+        } else if channel_end.state == ChannelState::Open
+            && remote_channel_end.state == ChannelState::TryOpen
+        {
+            let proof_ack = match read_verified_proof(
+                chain,
+                StorageItem::Channel(channel.0.clone(), channel.1),
+                Expected::Value(channel_end.encode()),
+                &block_header,
+            )
+            .await?
+            {
+                Some(proof) => proof,
+                None => continue,
+            };
+            let datagram = Datagram::ChanOpenConfirm {
+                port_identifier: channel_end.counterparty_port_identifier,
+                channel_identifier: channel_end.counterparty_channel_identifier,
+                proof_ack,
+                proof_height: block_number,
+            };
+            path.send_handshake(&tx, datagram);
+        } else {
+            path.handshakes.lock().unwrap().settle(
+                counterparty.name(),
+                HandshakeEnd::Channel(
+                    channel_end.counterparty_port_identifier,
+                    channel_end.counterparty_channel_identifier,
+                ),
+            );
+        }
+    }
+
+    for event in chain.ibc_events(block_hash).await? {
+        match event {
+            RawEvent::SendPacket(
+                sequence,
+                data,
+                timeout_height,
+                source_port,
+                source_channel,
+                dest_port,
+                dest_channel,
+            ) => {
+                if !path.filter.allows_channel(&source_port, source_channel) {
+                    debug!("[{}] SendPacket {} filtered out", chain_name, sequence);
+                    continue;
+                }
+                info!("[{}] SendPacket data: {:?}", chain_name, data);
+                let packet_data = Packet {
+                    sequence,
+                    timeout_height,
+                    source_port: source_port.clone(),
+                    source_channel,
+                    dest_port,
+                    dest_channel,
+                    data,
+                };
+                path.pending_packets.lock().unwrap().insert(
+                    chain_name,
+                    counterparty.name(),
+                    packet_data.clone(),
+                );
+                // The packet is timed out on the source chain by the opposite path instead.
+                let counterparty_height = counterparty.finalized_height().await?;
+                if packets::is_timed_out(&packet_data, counterparty_height) {
+                    info!(
+                        "[{}] packet {} already timed out on {} at #{}",
+                        chain_name,
+                        sequence,
+                        counterparty.name(),
+                        counterparty_height
+                    );
+                    continue;
+                }
+                let proof = match read_verified_proof(
+                    chain,
+                    StorageItem::Packet(source_port, source_channel, sequence),
+                    Expected::Exists,
+                    &block_header,
+                )
+                .await?
+                {
+                    Some(proof) => proof,
+                    None => continue,
+                };
+                let datagram = Datagram::PacketRecv {
+                    packet: packet_data,
+                    proof,
+                    proof_height: block_number,
+                };
+                tx.unbounded_send(datagram).unwrap();
+            }
+            RawEvent::RecvPacket(
+                sequence,
+                data,
+                timeout_height,
+                source_port,
+                source_channel,
+                dest_port,
+                dest_channel,
+                acknowledgement,
+            ) => {
+                debug!(
+                    "[{}] RecvPacket sequence: {}, data: {:?}, timeout_height: {}, \
+                             source_port: {:?}, source_channel: {:?}, dest_port: {:?}, \
+                             dest_channel: {:?}",
+                    chain_name,
+                    sequence,
+                    data,
+                    timeout_height,
+                    source_port,
+                    source_channel,
+                    dest_port,
+                    dest_channel
+                );
+                info!("[{}] RecvPacket data: {:?}", chain_name, data);
+                path.pending_packets.lock().unwrap().remove(
+                    counterparty.name(),
+                    chain_name,
+                    &source_port,
+                    source_channel,
+                    sequence,
+                );
+                if !path.filter.allows_channel(&dest_port, dest_channel) {
+                    debug!("[{}] RecvPacket {} filtered out", chain_name, sequence);
+                    continue;
+                }
+                // relay packet acknowledgement with this sequence number
+                let packet_data = Packet {
+                    sequence,
+                    timeout_height,
+                    source_port: source_port.clone(),
+                    source_channel,
+                    dest_port,
+                    dest_channel,
+                    data,
+                };
+                let proof = match read_verified_proof(
+                    chain,
+                    StorageItem::Acknowledgement(source_port, source_channel, sequence),
+                    Expected::Exists,
+                    &block_header,
+                )
+                .await?
+                {
+                    Some(proof) => proof,
+                    None => continue,
+                };
+                let datagram = Datagram::PacketAcknowledgement {
+                    packet: packet_data,
+                    acknowledgement,
+                    proof,
+                    proof_height: block_number,
+                };
+                tx.unbounded_send(datagram).unwrap();
+            }
+            _ => {}
+        }
+    }
+
+    // Packets sent by the counterparty which this chain can no longer receive are timed out. They
+    // stay pending until their commitment is gone from the counterparty, so that a timeout that
+    // failed to be submitted is sent again.
+    let timed_out_packets = path.pending_packets.lock().unwrap().timed_out(
+        counterparty.name(),
+        chain_name,
+        block_number,
+    );
+    let counterparty_hash = if timed_out_packets.is_empty() {
+        None
+    } else {
+        counterparty
+            .block_hash(counterparty.finalized_height().await?)
+            .await?
+    };
+    for packet in timed_out_packets {
+        let settled = match counterparty_hash {
+            Some(hash) => counterparty
+                .packet_commitment(
+                    packet.source_port.clone(),
+                    packet.source_channel,
+                    packet.sequence,
+                    hash,
+                )
+                .await?
+                .is_none(),
+            None => false,
+        };
+        let received = chain
+            .acknowledgement(
+                packet.source_port.clone(),
+                packet.source_channel,
+                packet.sequence,
+                block_hash,
+            )
+            .await?
+            .is_some();
+        if settled
+            || received
+            || !path
+                .filter
+                .allows_channel(&packet.dest_port, packet.dest_channel)
+        {
+            path.pending_packets.lock().unwrap().remove(
+                counterparty.name(),
+                chain_name,
+                &packet.source_port,
+                packet.source_channel,
+                packet.sequence,
+            );
+            continue;
+        }
+        if path.pending_packets.lock().unwrap().is_timing_out(
+            counterparty.name(),
+            chain_name,
+            &packet,
+        ) {
+            continue;
+        }
+        info!(
+            "[{}] packet {} timed out at #{}",
+            chain_name, packet.sequence, block_number
+        );
+        // Proves that the packet has not been received as of the timeout height.
+        if let Some(proof) = read_verified_proof(
+            chain,
+            StorageItem::Acknowledgement(
+                packet.source_port.clone(),
+                packet.source_channel,
+                packet.sequence,
+            ),
+            Expected::Absent,
+            &block_header,
+        )
+        .await?
+        {
+            path.pending_packets.lock().unwrap().set_timing_out(
+                counterparty.name(),
+                chain_name,
+                &packet,
+                true,
+            );
+            let datagram = Datagram::PacketTimeout {
+                packet,
+                proof,
+                proof_height: block_number,
+            };
+            tx.unbounded_send(datagram).unwrap();
+        }
+    }
+    let pending_packets = path
+        .pending_packets
+        .lock()
+        .unwrap()
+        .len(chain_name, counterparty.name());
+    path.metrics
+        .pending_packets
+        .with_label_values(&[&path.name()])
+        .set(pending_packets as i64);
+
+    Ok(())
+}
+
+/// Reads the consensus state of the destination chain of `path` that the client of it on the
+/// source chain holds for `height`, as of the block `header` of the source chain, and returns
+/// its height and a proof of it for the handshake step `step`.
+///
+/// Returns `None`, after logging why, when the client holds no consensus state for `height` or
+/// holds one that is not the one of the destination chain at that height: a connection opened
+/// on top of it could never verify anything relayed from the destination chain.
+async fn consensus_state_proof<C: Chain>(
+    path: &RelayPath<C>,
+    height: u32,
+    header: &Header,
+    step: &str,
+) -> Result<Option<(u32, StorageProof)>, Box<dyn Error>> {
+    let chain = &path.from;
+    let client_identifier = path.from_client_identifier;
+    let consensus_state = match chain
+        .consensus_state(client_identifier, height, Some(header.hash()))
+        .await?
+    {
+        Some(consensus_state) => consensus_state,
+        None => {
+            error!(
+                "[{}] client {:?} has no consensus state of {} at #{}, refusing to relay {}",
+                chain.name(),
+                client_identifier,
+                path.to.name(),
+                height,
+                step
+            );
+            return Ok(None);
+        }
+    };
+    let counterparty_root = match path.to.block_hash(height).await? {
+        Some(_) => Some(path.to.header(height).await?.state_root),
+        None => None,
+    };
+    if counterparty_root != Some(consensus_state.commitment_root) {
+        error!(
+            "[{}] client {:?} does not track {}: its state root at #{} is {:?} where {} has {:?}, \
+             refusing to relay {}",
+            chain.name(),
+            client_identifier,
+            path.to.name(),
+            height,
+            consensus_state.commitment_root,
+            path.to.name(),
+            counterparty_root,
+            step
+        );
+        return Ok(None);
+    }
+    let proof = read_verified_proof(
+        chain,
+        StorageItem::ConsensusState(client_identifier, height),
+        Expected::Value(consensus_state.encode()),
+        header,
+    )
+    .await?;
+    Ok(proof.map(|proof| (height, proof)))
+}
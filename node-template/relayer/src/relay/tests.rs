@@ -0,0 +1,933 @@
+use super::*;
+use crate::admin::{Admin, AdminPath};
+use crate::chain::IbcEvent;
+use crate::handshake::{HandshakeEnd, InFlightHandshakes};
+use crate::memory::MemoryChain;
+use crate::proof::{self, Expected};
+use async_std::task::block_on;
+use futures::channel::mpsc;
+use pallet_ibc::{ChannelEnd, ClientState, ConnectionEnd, ConsensusState};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const PORT: &[u8] = b"bank";
+
+fn appia_client() -> H256 {
+    H256::repeat_byte(0xa1)
+}
+
+fn flaminia_client() -> H256 {
+    H256::repeat_byte(0xf1)
+}
+
+fn appia_connection() -> H256 {
+    H256::repeat_byte(0xa2)
+}
+
+fn flaminia_connection() -> H256 {
+    H256::repeat_byte(0xf2)
+}
+
+fn appia_channel() -> H256 {
+    H256::repeat_byte(0xa3)
+}
+
+fn flaminia_channel() -> H256 {
+    H256::repeat_byte(0xf3)
+}
+
+/// Two chains with a client of each other, and the path from the first to the second.
+fn setup() -> (MemoryChain, MemoryChain, RelayPath<MemoryChain>) {
+    let appia = MemoryChain::new("appia");
+    let flaminia = MemoryChain::new("flaminia");
+    appia.insert_client(appia_client(), ClientState::default());
+    flaminia.insert_client(flaminia_client(), ClientState::default());
+    let path = path(&appia, &flaminia);
+    (appia, flaminia, path)
+}
+
+fn path(from: &MemoryChain, to: &MemoryChain) -> RelayPath<MemoryChain> {
+    let (from_client_identifier, to_client_identifier) = if from.name() == "appia" {
+        (appia_client(), flaminia_client())
+    } else {
+        (flaminia_client(), appia_client())
+    };
+    RelayPath {
+        from: from.clone(),
+        from_client_identifier,
+        to: to.clone(),
+        to_client_identifier,
+        filter: PathFilter::default(),
+        pending_packets: Default::default(),
+        handshakes: Default::default(),
+        metrics: Metrics::new().unwrap(),
+    }
+}
+
+/// Relays the block at `height` of the source chain of `path`, returning the datagrams sent
+/// besides client updates.
+fn relay_block(path: &RelayPath<MemoryChain>, height: u32) -> Vec<Datagram> {
+    let (tx, rx) = mpsc::unbounded();
+    let header = block_on(path.from.header(height)).unwrap();
+    block_on(relay(path, tx, header)).unwrap();
+    block_on(rx.collect::<Vec<_>>())
+        .into_iter()
+        .filter(|datagram| !matches!(datagram, Datagram::ClientUpdate { .. }))
+        .collect()
+}
+
+/// The consensus state of `chain` at `height`, as held by a client that tracks it.
+fn consensus_state(chain: &MemoryChain, height: u32) -> ConsensusState {
+    ConsensusState {
+        commitment_root: block_on(chain.header(height)).unwrap().state_root,
+        ..Default::default()
+    }
+}
+
+fn connection(state: ConnectionState, counterparty: H256) -> ConnectionEnd {
+    ConnectionEnd {
+        state,
+        counterparty_connection_identifier: counterparty,
+        ..Default::default()
+    }
+}
+
+fn send_packet_event(sequence: u64, timeout_height: u32) -> IbcEvent {
+    RawEvent::SendPacket(
+        sequence,
+        vec![1, 2, 3, 4],
+        timeout_height,
+        PORT.to_vec(),
+        appia_channel(),
+        PORT.to_vec(),
+        flaminia_channel(),
+    )
+}
+
+/// Sends a packet from appia to flaminia in the next block of `appia`.
+fn send_packet(appia: &MemoryChain, sequence: u64, timeout_height: u32) {
+    appia.insert_packet(PORT, appia_channel(), sequence, H256::repeat_byte(0xcc));
+    appia.deposit_event(send_packet_event(sequence, timeout_height));
+}
+
+#[test]
+fn relays_the_connection_handshake() {
+    let (appia, flaminia, appia_to_flaminia) = setup();
+    let flaminia_to_appia = path(&flaminia, &appia);
+    appia.insert_client(
+        appia_client(),
+        ClientState {
+            latest_height: 1,
+            connections: vec![appia_connection()],
+            ..Default::default()
+        },
+    );
+    flaminia.insert_client(
+        flaminia_client(),
+        ClientState {
+            latest_height: 1,
+            connections: vec![flaminia_connection()],
+            ..Default::default()
+        },
+    );
+
+    flaminia.produce_block();
+    appia.insert_consensus_state(appia_client(), 1, consensus_state(&flaminia, 1));
+    appia.insert_connection(
+        appia_connection(),
+        connection(ConnectionState::Init, flaminia_connection()),
+    );
+    appia.produce_block();
+    let datagrams = relay_block(&appia_to_flaminia, 1);
+    assert_eq!(datagrams.len(), 1);
+    match &datagrams[0] {
+        Datagram::ConnOpenTry {
+            desired_identifier,
+            counterparty_connection_identifier,
+            counterparty_client_identifier,
+            client_identifier,
+            proof_height,
+            consensus_height,
+            ..
+        } => {
+            assert_eq!(*desired_identifier, flaminia_connection());
+            assert_eq!(*counterparty_connection_identifier, appia_connection());
+            assert_eq!(*counterparty_client_identifier, appia_client());
+            assert_eq!(*client_identifier, flaminia_client());
+            assert_eq!(*proof_height, 1);
+            assert_eq!(*consensus_height, 1);
+        }
+        datagram => panic!("unexpected datagram: {:?}", datagram),
+    }
+
+    flaminia.insert_consensus_state(flaminia_client(), 1, consensus_state(&appia, 1));
+    flaminia.insert_connection(
+        flaminia_connection(),
+        connection(ConnectionState::TryOpen, appia_connection()),
+    );
+    flaminia.produce_block();
+    let datagrams = relay_block(&flaminia_to_appia, 2);
+    assert_eq!(datagrams.len(), 1);
+    match &datagrams[0] {
+        Datagram::ConnOpenAck {
+            identifier,
+            proof_height,
+            consensus_height,
+            ..
+        } => {
+            assert_eq!(*identifier, appia_connection());
+            assert_eq!(*proof_height, 2);
+            assert_eq!(*consensus_height, 1);
+        }
+        datagram => panic!("unexpected datagram: {:?}", datagram),
+    }
+
+    appia.insert_client(
+        appia_client(),
+        ClientState {
+            latest_height: 2,
+            connections: vec![appia_connection()],
+            ..Default::default()
+        },
+    );
+    appia.insert_connection(
+        appia_connection(),
+        connection(ConnectionState::Open, flaminia_connection()),
+    );
+    appia.produce_block();
+    let datagrams = relay_block(&appia_to_flaminia, 2);
+    assert_eq!(datagrams.len(), 1);
+    match &datagrams[0] {
+        Datagram::ConnOpenConfirm { identifier, .. } => {
+            assert_eq!(*identifier, flaminia_connection())
+        }
+        datagram => panic!("unexpected datagram: {:?}", datagram),
+    }
+}
+
+#[test]
+fn relays_chan_open_try() {
+    let (appia, flaminia, path) = setup();
+    appia.insert_client(
+        appia_client(),
+        ClientState {
+            channels: vec![(PORT.to_vec(), appia_channel())],
+            ..Default::default()
+        },
+    );
+    appia.insert_connection(
+        appia_connection(),
+        connection(ConnectionState::Open, flaminia_connection()),
+    );
+    appia.insert_channel(
+        PORT,
+        appia_channel(),
+        ChannelEnd {
+            state: ChannelState::Init,
+            counterparty_port_identifier: PORT.to_vec(),
+            counterparty_channel_identifier: flaminia_channel(),
+            connection_hops: vec![appia_connection()],
+            ..Default::default()
+        },
+    );
+    appia.produce_block();
+    flaminia.produce_block();
+
+    let datagrams = relay_block(&path, 1);
+    assert_eq!(datagrams.len(), 1);
+    match &datagrams[0] {
+        Datagram::ChanOpenTry {
+            connection_hops,
+            port_identifier,
+            channel_identifier,
+            counterparty_port_identifier,
+            counterparty_channel_identifier,
+            ..
+        } => {
+            assert_eq!(*connection_hops, vec![flaminia_connection()]);
+            assert_eq!(*port_identifier, PORT.to_vec());
+            assert_eq!(*channel_identifier, flaminia_channel());
+            assert_eq!(*counterparty_port_identifier, PORT.to_vec());
+            assert_eq!(*counterparty_channel_identifier, appia_channel());
+        }
+        datagram => panic!("unexpected datagram: {:?}", datagram),
+    }
+}
+
+#[test]
+fn relays_sent_packets_and_their_acknowledgements() {
+    let (appia, flaminia, appia_to_flaminia) = setup();
+    let mut flaminia_to_appia = path(&flaminia, &appia);
+    flaminia_to_appia.pending_packets = appia_to_flaminia.pending_packets.clone();
+
+    send_packet(&appia, 1, 0);
+    appia.produce_block();
+    flaminia.produce_block();
+    let datagrams = relay_block(&appia_to_flaminia, 1);
+    assert_eq!(datagrams.len(), 1);
+    match &datagrams[0] {
+        Datagram::PacketRecv { packet, .. } => {
+            assert_eq!(packet.sequence, 1);
+            assert_eq!(packet.source_channel, appia_channel());
+            assert_eq!(packet.dest_channel, flaminia_channel());
+        }
+        datagram => panic!("unexpected datagram: {:?}", datagram),
+    }
+    let pending_len = |path: &RelayPath<MemoryChain>| {
+        path.pending_packets
+            .lock()
+            .unwrap()
+            .len("appia", "flaminia")
+    };
+    assert_eq!(pending_len(&appia_to_flaminia), 1);
+
+    flaminia.deposit_event(RawEvent::RecvPacket(
+        1,
+        vec![1, 2, 3, 4],
+        0,
+        PORT.to_vec(),
+        appia_channel(),
+        PORT.to_vec(),
+        flaminia_channel(),
+        vec![1],
+    ));
+    flaminia.insert_acknowledgement(PORT, appia_channel(), 1, H256::repeat_byte(0xdd));
+    flaminia.produce_block();
+    let datagrams = relay_block(&flaminia_to_appia, 2);
+    assert_eq!(datagrams.len(), 1);
+    match &datagrams[0] {
+        Datagram::PacketAcknowledgement {
+            packet,
+            acknowledgement,
+            ..
+        } => {
+            assert_eq!(packet.sequence, 1);
+            assert_eq!(*acknowledgement, vec![1]);
+        }
+        datagram => panic!("unexpected datagram: {:?}", datagram),
+    }
+    assert_eq!(pending_len(&appia_to_flaminia), 0);
+}
+
+#[test]
+fn times_out_packets_the_counterparty_no_longer_receives() {
+    let (appia, flaminia, appia_to_flaminia) = setup();
+    let mut flaminia_to_appia = path(&flaminia, &appia);
+    flaminia_to_appia.pending_packets = appia_to_flaminia.pending_packets.clone();
+
+    send_packet(&appia, 1, 3);
+    appia.produce_block();
+    flaminia.produce_block();
+    assert_eq!(relay_block(&appia_to_flaminia, 1).len(), 1);
+
+    flaminia.produce_block();
+    assert!(relay_block(&flaminia_to_appia, 2).is_empty());
+    flaminia.produce_block();
+    let datagrams = relay_block(&flaminia_to_appia, 3);
+    assert_eq!(datagrams.len(), 1);
+    match &datagrams[0] {
+        Datagram::PacketTimeout {
+            packet,
+            proof_height,
+            ..
+        } => {
+            assert_eq!(packet.sequence, 1);
+            assert_eq!(*proof_height, 3);
+        }
+        datagram => panic!("unexpected datagram: {:?}", datagram),
+    }
+}
+
+#[test]
+fn keeps_timed_out_packets_pending_until_the_timeout_is_processed() {
+    let (appia, flaminia, appia_to_flaminia) = setup();
+    let mut flaminia_to_appia = path(&flaminia, &appia);
+    flaminia_to_appia.pending_packets = appia_to_flaminia.pending_packets.clone();
+    let pending_len = || {
+        appia_to_flaminia
+            .pending_packets
+            .lock()
+            .unwrap()
+            .len("appia", "flaminia")
+    };
+
+    send_packet(&appia, 1, 2);
+    appia.produce_block();
+    flaminia.produce_block();
+    assert_eq!(relay_block(&appia_to_flaminia, 1).len(), 1);
+    flaminia.produce_block();
+    let datagrams = relay_block(&flaminia_to_appia, 2);
+    let packet = match &datagrams[..] {
+        [Datagram::PacketTimeout { packet, .. }] => packet.clone(),
+        datagrams => panic!("unexpected datagrams: {:?}", datagrams),
+    };
+
+    // Not sent again while it is being submitted, but sent again once its submission failed.
+    flaminia.produce_block();
+    assert!(relay_block(&flaminia_to_appia, 3).is_empty());
+    appia_to_flaminia
+        .pending_packets
+        .lock()
+        .unwrap()
+        .set_timing_out("appia", "flaminia", &packet, false);
+    flaminia.produce_block();
+    assert_eq!(relay_block(&flaminia_to_appia, 4).len(), 1);
+    assert_eq!(pending_len(), 1);
+
+    // Forgotten once the timeout removed the commitment.
+    appia.remove_packet(PORT, appia_channel(), 1);
+    appia.produce_block();
+    flaminia.produce_block();
+    assert!(relay_block(&flaminia_to_appia, 5).is_empty());
+    assert_eq!(pending_len(), 0);
+}
+
+#[test]
+fn does_not_track_the_packets_of_a_path_without_its_opposite() {
+    let (appia, flaminia, path) = setup();
+    path.pending_packets
+        .lock()
+        .unwrap()
+        .track(std::iter::once(("flaminia".to_string(), "appia".to_string())).collect());
+    send_packet(&appia, 1, 5);
+    appia.produce_block();
+    flaminia.produce_block();
+
+    assert_eq!(relay_block(&path, 1).len(), 1);
+    assert_eq!(
+        path.pending_packets
+            .lock()
+            .unwrap()
+            .len("appia", "flaminia"),
+        0
+    );
+    assert_eq!(block_on(restore_pending_packets(&path, 1, 10)).unwrap(), 0);
+}
+
+#[test]
+fn restores_pending_packets_from_the_packet_commitments() {
+    let (appia, flaminia, path) = setup();
+    send_packet(&appia, 1, 5);
+    appia.produce_block();
+    send_packet(&appia, 2, 5);
+    appia.produce_block();
+    // Received, so it can no longer time out.
+    flaminia.insert_acknowledgement(PORT, appia_channel(), 2, H256::repeat_byte(0xdd));
+    flaminia.produce_block();
+    appia.produce_block();
+
+    assert_eq!(block_on(restore_pending_packets(&path, 3, 10)).unwrap(), 1);
+    let pending_packets = path.pending_packets.lock().unwrap();
+    let timed_out = pending_packets.timed_out("appia", "flaminia", 5);
+    assert_eq!(timed_out.len(), 1);
+    assert_eq!(timed_out[0].sequence, 1);
+    assert_eq!(timed_out[0].data, vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn skips_packets_already_timed_out() {
+    let (appia, flaminia, path) = setup();
+    flaminia.produce_block();
+    flaminia.produce_block();
+    send_packet(&appia, 1, 2);
+    appia.produce_block();
+
+    assert!(relay_block(&path, 1).is_empty());
+}
+
+#[test]
+fn skips_packets_of_filtered_channels() {
+    let (appia, flaminia, mut path) = setup();
+    path.filter = toml::from_str(&format!(
+        "deny_channels = [\"{}\"]",
+        hex::encode(appia_channel())
+    ))
+    .unwrap();
+    send_packet(&appia, 1, 0);
+    appia.produce_block();
+    flaminia.produce_block();
+
+    assert!(relay_block(&path, 1).is_empty());
+    assert_eq!(
+        path.pending_packets
+            .lock()
+            .unwrap()
+            .len("appia", "flaminia"),
+        0
+    );
+}
+
+#[test]
+fn updates_the_counterparty_client_to_the_newest_justified_header() {
+    let (appia, flaminia, path) = setup();
+    appia.produce_justified_block();
+    appia.produce_justified_block();
+    appia.produce_block();
+    flaminia.produce_block();
+
+    let (tx, rx) = mpsc::unbounded();
+    let header = block_on(appia.header(3)).unwrap();
+    block_on(relay(&path, tx, header)).unwrap();
+    let datagrams: Vec<_> = block_on(rx.collect());
+    assert_eq!(datagrams.len(), 1);
+    match &datagrams[0] {
+        Datagram::ClientUpdate { identifier, header } => {
+            assert_eq!(*identifier, flaminia_client());
+            assert_eq!(header.height, 2);
+        }
+        datagram => panic!("unexpected datagram: {:?}", datagram),
+    }
+}
+
+#[test]
+fn relay_blocks_moves_the_checkpoint() {
+    let (appia, flaminia, path) = setup();
+    appia.produce_block();
+    appia.produce_block();
+    flaminia.produce_block();
+    let file =
+        std::env::temp_dir().join(format!("relayer-checkpoints-{}.toml", std::process::id()));
+    let checkpoints = Mutex::new(CheckpointStore::open_read_only(&file).unwrap());
+    let (tx, _rx) = mpsc::unbounded();
+    let mut last_height = 0;
+
+    let control = PathControl::default();
+
+    block_on(relay_blocks(
+        &path,
+        tx,
+        &checkpoints,
+        &control,
+        &mut last_height,
+        2,
+    ))
+    .unwrap();
+    assert_eq!(last_height, 2);
+    assert_eq!(control.last_height(), 2);
+    assert_eq!(
+        checkpoints.lock().unwrap().get("appia", "flaminia"),
+        Some(2)
+    );
+}
+
+#[test]
+fn stopped_path_relays_no_more_blocks() {
+    let (appia, flaminia, path) = setup();
+    appia.produce_block();
+    flaminia.produce_block();
+    let file =
+        std::env::temp_dir().join(format!("relayer-checkpoints-{}.toml", std::process::id()));
+    let checkpoints = Arc::new(Mutex::new(CheckpointStore::open_read_only(&file).unwrap()));
+    let (tx, mut rx) = mpsc::unbounded();
+    let control = Arc::new(PathControl::default());
+    control.stop();
+    assert!(control.is_stopped());
+
+    // Returns instead of waiting for the next finalized block, dropping the sender.
+    block_on(follow(
+        path,
+        futures::stream::pending().boxed(),
+        tx,
+        checkpoints.clone(),
+        control.clone(),
+        0,
+        1,
+    ));
+    assert!(block_on(rx.next()).is_none());
+    assert_eq!(control.last_height(), 0);
+    assert_eq!(checkpoints.lock().unwrap().get("appia", "flaminia"), None);
+}
+
+#[test]
+fn refuses_proofs_that_do_not_check_out() {
+    let (appia, flaminia, path) = setup();
+    // The event of a packet whose commitment is missing from storage.
+    appia.deposit_event(send_packet_event(1, 0));
+    appia.produce_block();
+    flaminia.produce_block();
+
+    assert!(relay_block(&path, 1).is_empty());
+}
+
+#[test]
+fn verifies_proofs_against_the_state_root_of_their_block() {
+    let appia = MemoryChain::new("appia");
+    appia.insert_connection(
+        appia_connection(),
+        connection(ConnectionState::Init, flaminia_connection()),
+    );
+    let header = appia.produce_block();
+    appia.insert_connection(
+        appia_connection(),
+        connection(ConnectionState::Open, flaminia_connection()),
+    );
+    let next_header = appia.produce_block();
+    let item = StorageItem::Connection(appia_connection());
+    let key = appia.storage_key(&item).unwrap();
+    let proof = block_on(appia.read_proof(item, header.hash())).unwrap();
+    let init = Expected::Value(connection(ConnectionState::Init, flaminia_connection()).encode());
+
+    assert_eq!(
+        proof::verify(header.state_root, proof.clone(), &key, &init),
+        Ok(())
+    );
+    assert!(proof::verify(header.state_root, proof.clone(), &key, &Expected::Absent).is_err());
+    assert!(proof::verify(next_header.state_root, proof, &key, &init).is_err());
+}
+
+#[test]
+fn clears_outstanding_packets_and_acknowledgements() {
+    let (appia, flaminia, path) = setup();
+    send_packet(&appia, 1, 0);
+    send_packet(&appia, 2, 0);
+    appia.produce_justified_block();
+    flaminia.deposit_event(RawEvent::RecvPacket(
+        2,
+        vec![1, 2, 3, 4],
+        0,
+        PORT.to_vec(),
+        appia_channel(),
+        PORT.to_vec(),
+        flaminia_channel(),
+        vec![1],
+    ));
+    flaminia.insert_acknowledgement(PORT, appia_channel(), 2, H256::repeat_byte(0xdd));
+    flaminia.produce_justified_block();
+
+    let summary = block_on(crate::clear::clear_packets(
+        &path,
+        PORT.to_vec(),
+        appia_channel(),
+        Some(1),
+        Some(1),
+    ))
+    .unwrap();
+    assert_eq!(summary.received, vec![1]);
+    assert_eq!(summary.acknowledged, vec![2]);
+    assert!(summary.skipped.is_empty());
+
+    let to_flaminia = flaminia.take_submitted();
+    assert_eq!(to_flaminia.len(), 2);
+    assert!(matches!(to_flaminia[0], Datagram::ClientUpdate { .. }));
+    match &to_flaminia[1] {
+        Datagram::PacketRecv {
+            packet,
+            proof_height,
+            ..
+        } => {
+            assert_eq!(packet.sequence, 1);
+            assert_eq!(*proof_height, 1);
+        }
+        datagram => panic!("unexpected datagram: {:?}", datagram),
+    }
+    let to_appia = appia.take_submitted();
+    assert_eq!(to_appia.len(), 2);
+    assert!(matches!(to_appia[0], Datagram::ClientUpdate { .. }));
+    match &to_appia[1] {
+        Datagram::PacketAcknowledgement {
+            packet,
+            acknowledgement,
+            ..
+        } => {
+            assert_eq!(packet.sequence, 2);
+            assert_eq!(*acknowledgement, vec![1]);
+        }
+        datagram => panic!("unexpected datagram: {:?}", datagram),
+    }
+}
+
+#[test]
+fn sends_a_handshake_step_once_while_it_is_in_flight() {
+    let (appia, flaminia, path) = setup();
+    appia.insert_client(
+        appia_client(),
+        ClientState {
+            connections: vec![appia_connection()],
+            ..Default::default()
+        },
+    );
+    appia.insert_consensus_state(appia_client(), 0, consensus_state(&flaminia, 0));
+    appia.insert_connection(
+        appia_connection(),
+        connection(ConnectionState::Init, flaminia_connection()),
+    );
+    appia.produce_block();
+    appia.produce_block();
+    flaminia.produce_block();
+
+    assert_eq!(relay_block(&path, 1).len(), 1);
+    assert!(relay_block(&path, 2).is_empty());
+    assert_eq!(path.handshakes.lock().unwrap().len("flaminia"), 1);
+
+    // A failed submission lets the next block send the step again.
+    path.handshakes
+        .lock()
+        .unwrap()
+        .settle("flaminia", HandshakeEnd::Connection(flaminia_connection()));
+    assert_eq!(relay_block(&path, 2).len(), 1);
+}
+
+#[test]
+fn resends_a_handshake_step_once_it_timed_out() {
+    let (appia, flaminia, mut path) = setup();
+    path.handshakes = Arc::new(Mutex::new(InFlightHandshakes::new(Duration::from_secs(0))));
+    appia.insert_client(
+        appia_client(),
+        ClientState {
+            connections: vec![appia_connection()],
+            ..Default::default()
+        },
+    );
+    appia.insert_consensus_state(appia_client(), 0, consensus_state(&flaminia, 0));
+    appia.insert_connection(
+        appia_connection(),
+        connection(ConnectionState::Init, flaminia_connection()),
+    );
+    appia.produce_block();
+    appia.produce_block();
+    flaminia.produce_block();
+
+    assert_eq!(relay_block(&path, 1).len(), 1);
+    assert_eq!(relay_block(&path, 2).len(), 1);
+}
+
+#[test]
+fn forgets_a_handshake_step_once_its_end_moved_on() {
+    let (appia, flaminia, path) = setup();
+    appia.insert_client(
+        appia_client(),
+        ClientState {
+            latest_height: 1,
+            connections: vec![appia_connection()],
+            ..Default::default()
+        },
+    );
+    flaminia.produce_block();
+    appia.insert_consensus_state(appia_client(), 1, consensus_state(&flaminia, 1));
+    appia.insert_connection(
+        appia_connection(),
+        connection(ConnectionState::Init, flaminia_connection()),
+    );
+    appia.produce_block();
+    assert_eq!(relay_block(&path, 1).len(), 1);
+
+    flaminia.insert_connection(
+        flaminia_connection(),
+        connection(ConnectionState::TryOpen, appia_connection()),
+    );
+    flaminia.produce_block();
+    appia.insert_client(
+        appia_client(),
+        ClientState {
+            latest_height: 2,
+            connections: vec![appia_connection()],
+            ..Default::default()
+        },
+    );
+    appia.produce_block();
+    assert!(relay_block(&path, 2).is_empty());
+    assert_eq!(path.handshakes.lock().unwrap().len("flaminia"), 0);
+}
+
+#[test]
+fn records_packets_sent_in_unfinalized_blocks_as_pending() {
+    let (appia, _flaminia, path) = setup();
+    let mut blocks = block_on(appia.unfinalized_ibc_events());
+    send_packet(&appia, 1, 0);
+    appia.produce_block();
+
+    let (header, events) = block_on(blocks.next()).unwrap();
+    assert_eq!(header.number, 1);
+    record_unfinalized_events(&path, &header, events);
+    assert_eq!(
+        path.pending_packets
+            .lock()
+            .unwrap()
+            .len("appia", "flaminia"),
+        1
+    );
+}
+
+#[test]
+fn refuses_the_handshake_with_a_client_of_another_chain() {
+    let (appia, flaminia, path) = setup();
+    appia.insert_client(
+        appia_client(),
+        ClientState {
+            latest_height: 1,
+            connections: vec![appia_connection()],
+            ..Default::default()
+        },
+    );
+    flaminia.produce_block();
+    // The state root of some other chain at #1.
+    appia.insert_consensus_state(
+        appia_client(),
+        1,
+        ConsensusState {
+            commitment_root: H256::repeat_byte(0xee),
+            ..Default::default()
+        },
+    );
+    appia.insert_connection(
+        appia_connection(),
+        connection(ConnectionState::Init, flaminia_connection()),
+    );
+    appia.produce_block();
+
+    assert!(relay_block(&path, 1).is_empty());
+}
+
+#[test]
+fn submits_misbehaviour_of_a_client_holding_a_conflicting_header() {
+    let (appia, flaminia, path) = setup();
+    appia.produce_justified_block();
+    flaminia.insert_client(
+        flaminia_client(),
+        ClientState {
+            latest_height: 1,
+            ..Default::default()
+        },
+    );
+    flaminia.insert_consensus_state(flaminia_client(), 1, consensus_state(&appia, 1));
+    flaminia.produce_block();
+    let mut checked_height = None;
+    assert!(block_on(crate::misbehaviour::find_misbehaviour(
+        &path,
+        &mut checked_height
+    ))
+    .unwrap()
+    .is_none());
+
+    // A header of another block at the same height.
+    flaminia.insert_client(
+        flaminia_client(),
+        ClientState {
+            latest_height: 2,
+            ..Default::default()
+        },
+    );
+    appia.produce_justified_block();
+    flaminia.insert_consensus_state(
+        flaminia_client(),
+        2,
+        ConsensusState {
+            commitment_root: H256::repeat_byte(0xee),
+            ..Default::default()
+        },
+    );
+    flaminia.produce_block();
+    let evidence = block_on(crate::misbehaviour::find_misbehaviour(
+        &path,
+        &mut checked_height,
+    ))
+    .unwrap();
+    match evidence {
+        Some(Datagram::ClientMisbehaviour {
+            identifier,
+            evidence,
+        }) => {
+            assert_eq!(identifier, flaminia_client());
+            assert_eq!(evidence.height, 2);
+            assert_eq!(
+                evidence.commitment_root,
+                block_on(appia.header(2)).unwrap().state_root
+            );
+        }
+        evidence => panic!("unexpected evidence: {:?}", evidence),
+    }
+    // The conflict is reported once.
+    assert!(block_on(crate::misbehaviour::find_misbehaviour(
+        &path,
+        &mut checked_height
+    ))
+    .unwrap()
+    .is_none());
+}
+
+#[test]
+fn finds_misbehaviour_below_the_latest_height_of_a_batched_update() {
+    let (appia, flaminia, path) = setup();
+    appia.produce_justified_block();
+    flaminia.insert_client(
+        flaminia_client(),
+        ClientState {
+            latest_height: 1,
+            ..Default::default()
+        },
+    );
+    flaminia.insert_consensus_state(flaminia_client(), 1, consensus_state(&appia, 1));
+    flaminia.produce_block();
+    let mut checked_height = None;
+    assert!(block_on(crate::misbehaviour::find_misbehaviour(
+        &path,
+        &mut checked_height
+    ))
+    .unwrap()
+    .is_none());
+
+    // Updated to #2 and #3 at once, with a conflicting header at #2 only.
+    appia.produce_justified_block();
+    appia.produce_justified_block();
+    flaminia.insert_client(
+        flaminia_client(),
+        ClientState {
+            latest_height: 3,
+            ..Default::default()
+        },
+    );
+    flaminia.insert_consensus_state(
+        flaminia_client(),
+        2,
+        ConsensusState {
+            commitment_root: H256::repeat_byte(0xee),
+            ..Default::default()
+        },
+    );
+    flaminia.insert_consensus_state(flaminia_client(), 3, consensus_state(&appia, 3));
+    flaminia.produce_block();
+    match block_on(crate::misbehaviour::find_misbehaviour(
+        &path,
+        &mut checked_height,
+    ))
+    .unwrap()
+    {
+        Some(Datagram::ClientMisbehaviour { evidence, .. }) => assert_eq!(evidence.height, 2),
+        evidence => panic!("unexpected evidence: {:?}", evidence),
+    }
+    assert_eq!(checked_height, Some(2));
+}
+
+#[test]
+fn admin_inspects_and_steers_paths() {
+    let (appia, flaminia, path) = setup();
+    send_packet(&appia, 1, 0);
+    appia.produce_justified_block();
+    flaminia.produce_block();
+    relay_block(&path, 1);
+    let control = Arc::new(PathControl::default());
+    let admin = Admin::new(vec![AdminPath {
+        path: path.clone(),
+        control: control.clone(),
+    }]);
+
+    let paths = block_on(admin.call("paths", &Value::Null)).unwrap();
+    assert_eq!(paths[0]["path"], "appia:flaminia");
+    assert_eq!(paths[0]["paused"], false);
+    assert_eq!(paths[0]["client_height"], 0);
+
+    block_on(admin.call("pause", &json!(["appia:flaminia"]))).unwrap();
+    assert!(control.is_paused());
+    block_on(admin.call("resume", &json!({ "path": "appia:flaminia" }))).unwrap();
+    assert!(!control.is_paused());
+    assert!(block_on(admin.call("pause", &json!(["appia:roma"]))).is_err());
+
+    let channels = block_on(admin.call("pending_packets", &json!(["appia:flaminia"]))).unwrap();
+    assert_eq!(channels[0]["port"], "bank");
+    assert_eq!(channels[0]["sequences"], json!([1]));
+
+    let update = block_on(admin.call("update_client", &json!(["appia:flaminia"]))).unwrap();
+    assert_eq!(update["updates"], 1);
+    let submitted = flaminia.take_submitted();
+    assert!(matches!(submitted[..], [Datagram::ClientUpdate { .. }]));
+}
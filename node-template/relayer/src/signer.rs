@@ -0,0 +1,68 @@
+//! Loading of the keys the relayer signs datagrams with.
+use serde_derive::Deserialize;
+use sp_core::{sr25519, Pair};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+/// Where the signing key of a chain comes from.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignerConfig {
+    /// A secret URI, e.g. `//Alice` or `<mnemonic>//hard/soft///password`.
+    SecretUri(String),
+    /// A file holding a BIP39 mnemonic phrase.
+    MnemonicFile(PathBuf),
+    /// A key file of a Substrate keystore, unlocked with the password held by the environment
+    /// variable `password_env`.
+    Keystore {
+        path: PathBuf,
+        password_env: Option<String>,
+    },
+}
+
+// Secrets must never end up in the logs, only say where they are read from.
+impl fmt::Debug for SignerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignerConfig::SecretUri(_) => f.write_str("SecretUri(..)"),
+            SignerConfig::MnemonicFile(path) => f.debug_tuple("MnemonicFile").field(path).finish(),
+            SignerConfig::Keystore { path, password_env } => f
+                .debug_struct("Keystore")
+                .field("path", path)
+                .field("password_env", password_env)
+                .finish(),
+        }
+    }
+}
+
+/// Builds the key pair described by `config`.
+pub fn load_pair(config: &SignerConfig) -> Result<sr25519::Pair, Box<dyn Error>> {
+    match config {
+        SignerConfig::SecretUri(suri) => sr25519::Pair::from_string(suri, None)
+            .map_err(|e| format!("invalid secret uri: {:?}", e).into()),
+        SignerConfig::MnemonicFile(path) => {
+            let phrase = fs::read_to_string(path)
+                .map_err(|e| format!("can not read {}: {}", path.display(), e))?;
+            sr25519::Pair::from_phrase(phrase.trim(), None)
+                .map(|(pair, _seed)| pair)
+                .map_err(|e| format!("invalid mnemonic in {}: {:?}", path.display(), e).into())
+        }
+        SignerConfig::Keystore { path, password_env } => {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| format!("can not read {}: {}", path.display(), e))?;
+            // Substrate keystores store the secret phrase or seed of a key as a JSON string.
+            let phrase: String = serde_json::from_str(&contents)?;
+            let password = match password_env {
+                Some(name) => {
+                    Some(env::var(name).map_err(|e| format!("can not read ${}: {}", name, e))?)
+                }
+                None => None,
+            };
+            sr25519::Pair::from_string(&phrase, password.as_deref())
+                .map_err(|e| format!("invalid key in {}: {:?}", path.display(), e).into())
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! Submission of datagrams to a chain, keeping the signer's nonce in sync with the chain.
+use crate::{chain::Chain, datagram, metrics::Metrics, subxt_chain::SubxtChain};
+use calls::{ibc::SubmitDatagramCall, NodeRuntime as Runtime};
+use futures::channel::{mpsc::UnboundedReceiver, oneshot};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{error, info, warn};
+use pallet_ibc::Datagram;
+use sp_core::sr25519;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+use substrate_subxt::{system::AccountStoreExt, Error, ExtrinsicSuccess, PairSigner, Signer};
+
+/// How many times a datagram is submitted before it is dropped.
+const MAX_ATTEMPTS: u32 = 5;
+/// Delay before the first retry, doubled after every further failure.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// How long a submitted extrinsic may take to be included before its submission counts as failed.
+const INCLUSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A datagram waiting in the queue of a chain, with where to report its outcome.
+pub struct Queued {
+    pub path: String,
+    pub datagram: Datagram,
+    pub outcome: oneshot::Sender<Result<(), String>>,
+}
+
+/// A queued datagram being submitted, how many times it was and the nonce it is signed with.
+struct Attempt {
+    queued: Queued,
+    attempt: u32,
+    started: Instant,
+    nonce: Option<u32>,
+}
+
+/// What became of an attempt once its submission ended.
+enum Settled {
+    Done,
+    Retry(Attempt),
+    GaveUp,
+}
+
+/// An attempt whose extrinsic was included, or that failed.
+type Watched = BoxFuture<'static, (Attempt, Result<ExtrinsicSuccess<Runtime>, Error>)>;
+
+/// Submits datagrams to one chain, in the order they are queued, signed by a single account.
+///
+/// There is one submitter per chain, draining the queue every path relaying to it feeds, so that
+/// the nonce of the account is never raced for. It goes through the connection of the chain and
+/// so shares it, and its reconnections, with the paths.
+///
+/// Datagrams are signed with a nonce kept locally, incremented for each of them, and submitted
+/// without waiting for the previous ones to be included. As the chain includes the extrinsics of
+/// an account in the order of their nonces, a retried datagram is signed again with the nonce it
+/// was first given, so that it still lands before the datagrams queued after it. Datagrams queued
+/// while one is being retried are held back until it is settled, and if it was given up on, until
+/// every submission ended and the nonce was read from the chain again, so that none waits behind
+/// the nonce left unused.
+pub struct Submitter {
+    chain_name: String,
+    chain: SubxtChain,
+    signer: PairSigner<Runtime, sr25519::Pair>,
+    next_nonce: u32,
+    metrics: Metrics,
+}
+
+impl Submitter {
+    pub async fn new(
+        chain: SubxtChain,
+        pair: sr25519::Pair,
+        metrics: Metrics,
+    ) -> Result<Self, Error> {
+        let mut submitter = Self {
+            chain_name: chain.name().to_string(),
+            chain,
+            signer: PairSigner::new(pair),
+            next_nonce: 0,
+            metrics,
+        };
+        submitter.sync_nonce().await?;
+        info!(
+            "[relayer => {}] signer: {}, nonce: {:?}",
+            submitter.chain_name,
+            submitter.signer.account_id(),
+            submitter.next_nonce
+        );
+        Ok(submitter)
+    }
+
+    /// Submits the datagrams of `queue` until every sender of the queue is dropped and every
+    /// datagram submitted is included or given up on.
+    ///
+    /// Transient failures, including extrinsics not included in time, are retried with exponential
+    /// backoff, reconnecting if the node went away. A datagram whose extrinsic was included but
+    /// failed is not retried, as the runtime would reject it again.
+    pub async fn run(mut self, mut queue: UnboundedReceiver<Queued>) {
+        let mut in_flight = FuturesUnordered::new();
+        let mut retries = FuturesUnordered::new();
+        let mut held_back = VecDeque::new();
+        // Datagrams being retried, whether waiting for their backoff or in flight again.
+        let mut retrying = 0usize;
+        // Whether a datagram was given up on since the nonce was last read from the chain.
+        let mut nonce_unused = false;
+        loop {
+            futures::select! {
+                queued = queue.next() => match queued {
+                    Some(queued) => held_back.push_back(Attempt {
+                        queued,
+                        attempt: 1,
+                        started: Instant::now(),
+                        nonce: None,
+                    }),
+                    None => continue,
+                },
+                (attempt, result) = in_flight.select_next_some() => {
+                    let retried = attempt.attempt > 1;
+                    match self.settle(attempt, result).await {
+                        Settled::Retry(attempt) => {
+                            if !retried {
+                                retrying += 1;
+                            }
+                            let backoff = INITIAL_BACKOFF * 2u32.pow(attempt.attempt - 1);
+                            retries.push(async move {
+                                async_std::task::sleep(backoff).await;
+                                attempt
+                            });
+                        }
+                        Settled::GaveUp => {
+                            retrying -= 1;
+                            nonce_unused = true;
+                        }
+                        Settled::Done => {
+                            if retried {
+                                retrying -= 1;
+                            }
+                        }
+                    }
+                }
+                mut attempt = retries.select_next_some() => {
+                    if !self.chain.is_alive().await {
+                        warn!(
+                            "[relayer => {}] lost connection, reconnecting",
+                            self.chain_name
+                        );
+                        self.chain.reconnect().await;
+                    }
+                    attempt.attempt += 1;
+                    attempt.started = Instant::now();
+                    in_flight.push(self.send(attempt).await);
+                }
+                complete => break,
+            }
+            if retrying > 0 {
+                continue;
+            }
+            if nonce_unused {
+                // Extrinsics signed after the unused nonce can't be included, wait until they
+                // were given up on too, then sign from the nonce of the account on chain.
+                if !in_flight.is_empty() {
+                    continue;
+                }
+                if let Err(e) = self.sync_nonce().await {
+                    warn!(
+                        "[relayer => {}] failed to read nonce; error = {}",
+                        self.chain_name, e
+                    );
+                    continue;
+                }
+                nonce_unused = false;
+            }
+            while let Some(attempt) = held_back.pop_front() {
+                in_flight.push(self.send(attempt).await);
+            }
+        }
+    }
+
+    /// Signs the datagram of `attempt`, with the nonce it was given or else the next one, and
+    /// submits it, returning the future that watches it until it is included.
+    async fn send(&mut self, mut attempt: Attempt) -> Watched {
+        let client = self.chain.client();
+        let call = SubmitDatagramCall::<Runtime> {
+            _runtime: PhantomData,
+            datagram: attempt.queued.datagram.clone(),
+        };
+        let nonce = match attempt.nonce {
+            Some(nonce) => nonce,
+            None => {
+                let nonce = self.next_nonce;
+                self.next_nonce += 1;
+                attempt.nonce = Some(nonce);
+                nonce
+            }
+        };
+        self.signer.set_nonce(nonce);
+        match client.create_signed(call, &self.signer).await {
+            Ok(extrinsic) => {
+                let decoder = client.events_decoder::<SubmitDatagramCall<Runtime>>();
+                async move {
+                    let watch = client.submit_and_watch_extrinsic(extrinsic, decoder);
+                    let result = match async_std::future::timeout(INCLUSION_TIMEOUT, watch).await {
+                        Ok(result) => result,
+                        Err(_) => Err(Error::Other(format!(
+                            "not included within {:?}",
+                            INCLUSION_TIMEOUT
+                        ))),
+                    };
+                    (attempt, result)
+                }
+                .boxed()
+            }
+            Err(e) => async move { (attempt, Err(e)) }.boxed(),
+        }
+    }
+
+    /// Reports the outcome of `attempt`, or returns it if it is to be retried.
+    async fn settle(
+        &mut self,
+        attempt: Attempt,
+        result: Result<ExtrinsicSuccess<Runtime>, Error>,
+    ) -> Settled {
+        let labels = [
+            attempt.queued.path.as_str(),
+            datagram::kind(&attempt.queued.datagram),
+        ];
+        let mut settled = Settled::Done;
+        let outcome = match result {
+            Ok(success) => {
+                self.metrics
+                    .submission_latency
+                    .with_label_values(&labels)
+                    .observe(attempt.started.elapsed().as_secs_f64());
+                self.metrics
+                    .datagrams_submitted
+                    .with_label_values(&labels)
+                    .inc();
+                if success
+                    .find_event_raw("System", "ExtrinsicSuccess")
+                    .is_some()
+                {
+                    info!(
+                        "[relayer => {}] datagram included in block {:?}",
+                        self.chain_name, success.block
+                    );
+                }
+                self.update_account_metrics().await;
+                Ok(())
+            }
+            Err(e @ Error::Runtime(_)) => {
+                // ExtrinsicFailed, the extrinsic is included so its nonce is used up.
+                self.metrics
+                    .submission_latency
+                    .with_label_values(&labels)
+                    .observe(attempt.started.elapsed().as_secs_f64());
+                self.metrics
+                    .datagrams_failed
+                    .with_label_values(&labels)
+                    .inc();
+                let e = format!("datagram rejected by the runtime: {}", e);
+                error!("[relayer => {}] {}", self.chain_name, e);
+                self.update_account_metrics().await;
+                Err(e)
+            }
+            Err(e) => {
+                warn!(
+                    "[relayer => {}] failed to submit datagram (attempt {}/{}); error = {}",
+                    self.chain_name, attempt.attempt, MAX_ATTEMPTS, e
+                );
+                if attempt.attempt < MAX_ATTEMPTS {
+                    return Settled::Retry(attempt);
+                }
+                self.metrics
+                    .datagrams_failed
+                    .with_label_values(&labels)
+                    .inc();
+                error!(
+                    "[relayer => {}] giving up on datagram after {} attempts: {:?}",
+                    self.chain_name, MAX_ATTEMPTS, attempt.queued.datagram
+                );
+                settled = Settled::GaveUp;
+                Err(format!("gave up after {} attempts", MAX_ATTEMPTS))
+            }
+        };
+        // Whoever queued the datagram may have stopped waiting for it.
+        let _ = attempt.queued.outcome.send(outcome);
+        settled
+    }
+
+    /// Sets the nonce of the signer to the one of its account on chain.
+    async fn sync_nonce(&mut self) -> Result<(), Error> {
+        let account = self
+            .chain
+            .client()
+            .account(self.signer.account_id(), None)
+            .await?;
+        self.next_nonce = account.nonce;
+        self.set_account_metrics(account.nonce, account.data.free);
+        Ok(())
+    }
+
+    async fn update_account_metrics(&self) {
+        match self
+            .chain
+            .client()
+            .account(self.signer.account_id(), None)
+            .await
+        {
+            Ok(account) => self.set_account_metrics(account.nonce, account.data.free),
+            Err(e) => warn!(
+                "[relayer => {}] failed to read signer account; error = {}",
+                self.chain_name, e
+            ),
+        }
+    }
+
+    fn set_account_metrics(&self, nonce: u32, free_balance: u128) {
+        self.metrics
+            .signer_nonce
+            .with_label_values(&[&self.chain_name])
+            .set(nonce.into());
+        self.metrics
+            .signer_balance
+            .with_label_values(&[&self.chain_name])
+            .set(free_balance as f64);
+    }
+}
@@ -0,0 +1,570 @@
+//! A chain behind a node, reached through substrate-subxt.
+use crate::chain::{Chain, EventStream, Header, HeaderStream, IbcEvent, StorageItem, Submission};
+use crate::submitter::{Queued, Submitter};
+use crate::{connection, connection::FINALITY_TIMEOUT, metrics::Metrics};
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use calls::{
+    ibc::{
+        self, ChannelsStoreExt, ClientsStoreExt, ConnectionsStoreExt, IbcEventsDecoder,
+        RecvPacketEvent, SendPacketEvent,
+    },
+    NodeRuntime as Runtime,
+};
+use codec::Decode;
+use futures::channel::{
+    mpsc::{self, UnboundedSender},
+    oneshot,
+};
+use futures::future::{self, FutureExt};
+use futures::stream::{self, StreamExt};
+use jsonrpsee::client::Subscription;
+use log::warn;
+use pallet_ibc::{ChannelEnd, ClientState, ConnectionEnd, ConsensusState, Datagram};
+use sp_core::{
+    sr25519,
+    storage::{StorageChangeSet, StorageKey},
+    H256,
+};
+use sp_finality_grandpa::{AuthorityList, VersionedAuthorityList, GRANDPA_AUTHORITIES_KEY};
+use sp_runtime::{traits::Header as _, Justification};
+use sp_trie::StorageProof;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::{Arc, Mutex as StdMutex, RwLock};
+use std::time::Duration;
+use substrate_subxt::{BlockNumber, Client, Event, EventsDecoder, RawEvent, Store};
+
+/// How many storage keys are read at once when going through a map.
+const KEYS_PAGE_SIZE: u32 = 256;
+/// How many of the latest blocks have their IBC events kept.
+const CACHED_BLOCK_EVENTS: usize = 256;
+/// How long to wait before subscribing again after a subscription failed.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// A chain behind one connection to its node, shared by every clone and so by every path.
+///
+/// Finalized heads come from a single subscription fanned out to all subscribers, events from a
+/// single subscription to the changes of `System.Events`, and datagrams
+/// go through a single queue, drained by the submitter of the chain.
+#[derive(Clone)]
+pub struct SubxtChain {
+    name: String,
+    endpoint: String,
+    client: Arc<RwLock<Client<Runtime>>>,
+    /// Held while reconnecting, so that paths noticing a dead connection together reconnect once.
+    reconnecting: Arc<Mutex<()>>,
+    /// Subscribers to the finalized heads, `None` until the subscription is made.
+    heads: Arc<StdMutex<Option<Vec<UnboundedSender<Header>>>>>,
+    /// IBC events of the latest blocks announced by the node, oldest first, `None` until the
+    /// subscription to them is made.
+    events: Arc<StdMutex<Option<VecDeque<(H256, Vec<IbcEvent>)>>>>,
+    /// Queue of the datagrams to submit, absent until a signer is set.
+    queue: Option<UnboundedSender<Queued>>,
+}
+
+impl SubxtChain {
+    /// Connects to the node of `name` at `endpoint`, retrying until it is reachable.
+    pub async fn connect(name: &str, endpoint: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            endpoint: endpoint.to_string(),
+            client: Arc::new(RwLock::new(connection::connect(name, endpoint).await)),
+            reconnecting: Default::default(),
+            heads: Default::default(),
+            events: Default::default(),
+            queue: None,
+        }
+    }
+
+    /// Signs the datagrams submitted to the chain with `pair`.
+    ///
+    /// Only clones made afterwards submit datagrams, so the signer is set before the chain is
+    /// handed to its paths.
+    pub async fn set_signer(
+        &mut self,
+        pair: sr25519::Pair,
+        metrics: Metrics,
+    ) -> Result<(), Box<dyn Error>> {
+        let submitter = Submitter::new(self.clone(), pair, metrics).await?;
+        let (sender, receiver) = mpsc::unbounded();
+        async_std::task::spawn(submitter.run(receiver));
+        self.queue = Some(sender);
+        Ok(())
+    }
+
+    pub fn has_signer(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// Returns the current connection to the node.
+    pub fn client(&self) -> Client<Runtime> {
+        self.client.read().unwrap().clone()
+    }
+
+    /// Subscribes to the finalized heads, reconnecting if the subscription cannot be made.
+    async fn subscribe_finalized_heads(&self) -> Subscription<Header> {
+        match self.client().subscribe_finalized_blocks().await {
+            Ok(headers) => headers,
+            Err(e) => {
+                warn!(
+                    "[{}] failed to subscribe to finalized heads; error = {}",
+                    self.name, e
+                );
+                let _reconnecting = self.reconnecting.lock().await;
+                let (client, headers) =
+                    connection::subscribe_finalized_heads(&self.name, &self.endpoint).await;
+                *self.client.write().unwrap() = client;
+                headers
+            }
+        }
+    }
+
+    /// Forwards the finalized heads of the chain to its subscribers for as long as the relayer
+    /// runs, resubscribing whenever the node goes away.
+    async fn fan_out_finalized_heads(self) {
+        let mut headers = self.subscribe_finalized_heads().await;
+        loop {
+            match async_std::future::timeout(FINALITY_TIMEOUT, headers.next()).await {
+                Ok(header) => {
+                    if let Some(subscribers) = self.heads.lock().unwrap().as_mut() {
+                        subscribers
+                            .retain(|subscriber| subscriber.unbounded_send(header.clone()).is_ok());
+                    }
+                }
+                Err(_) => {
+                    if !self.is_alive().await {
+                        warn!("[{}] lost connection, reconnecting", self.name);
+                        self.reconnect_shared().await;
+                        headers = self.subscribe_finalized_heads().await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Subscribes to the events of the blocks the node imports, reconnecting until the
+    /// subscription can be made.
+    async fn subscribe_events(&self) -> Subscription<StorageChangeSet<H256>> {
+        loop {
+            match self.client().subscribe_events().await {
+                Ok(change_sets) => return change_sets,
+                Err(e) => {
+                    warn!(
+                        "[{}] failed to subscribe to events; error = {}",
+                        self.name, e
+                    );
+                    async_std::task::sleep(RESUBSCRIBE_DELAY).await;
+                    self.reconnect_shared().await;
+                }
+            }
+        }
+    }
+
+    /// Decodes and keeps the IBC events of the blocks the node imports for as long as the relayer
+    /// runs, so that relaying a block does not read its events again, resubscribing whenever the
+    /// node goes away.
+    async fn cache_ibc_events(self) {
+        let mut change_sets = self.subscribe_events().await;
+        loop {
+            match async_std::future::timeout(FINALITY_TIMEOUT, change_sets.next()).await {
+                Ok(change_set) => {
+                    let decoded = events_decoder(&self.client())
+                        .and_then(|decoder| decode_ibc_events(&decoder, &change_set));
+                    let block_events = match decoded {
+                        Ok(block_events) => block_events,
+                        Err(e) => {
+                            // Read from storage again when the block is relayed.
+                            warn!(
+                                "[{}] failed to decode the events of {:?}; error = {}",
+                                self.name, change_set.block, e
+                            );
+                            continue;
+                        }
+                    };
+                    let mut events = self.events.lock().unwrap();
+                    let events = events.get_or_insert_with(VecDeque::new);
+                    events.push_back((change_set.block, block_events));
+                    if events.len() > CACHED_BLOCK_EVENTS {
+                        events.pop_front();
+                    }
+                }
+                Err(_) => {
+                    if !self.is_alive().await {
+                        warn!("[{}] lost connection, reconnecting", self.name);
+                        self.reconnect_shared().await;
+                        change_sets = self.subscribe_events().await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replaces the connection unless another path already did.
+    async fn reconnect_shared(&self) {
+        let _reconnecting = self.reconnecting.lock().await;
+        if !self.is_alive().await {
+            let client = connection::connect(&self.name, &self.endpoint).await;
+            *self.client.write().unwrap() = client;
+        }
+    }
+}
+
+#[async_trait]
+impl Chain for SubxtChain {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn finalized_heads(&mut self) -> HeaderStream {
+        let (sender, receiver) = mpsc::unbounded();
+        let subscribed = {
+            let mut heads = self.heads.lock().unwrap();
+            let subscribed = heads.is_some();
+            heads.get_or_insert_with(Vec::new).push(sender);
+            subscribed
+        };
+        if !subscribed {
+            async_std::task::spawn(self.clone().fan_out_finalized_heads());
+        }
+        receiver.boxed()
+    }
+
+    async fn is_alive(&self) -> bool {
+        connection::is_alive(&self.client()).await
+    }
+
+    async fn reconnect(&mut self) {
+        self.reconnect_shared().await;
+    }
+
+    async fn finalized_height(&self) -> Result<u32, Box<dyn Error>> {
+        connection::finalized_head_height(&self.client()).await
+    }
+
+    async fn block_hash(&self, height: u32) -> Result<Option<H256>, Box<dyn Error>> {
+        Ok(self
+            .client()
+            .block_hash(Some(BlockNumber::from(height)))
+            .await?)
+    }
+
+    async fn header(&self, height: u32) -> Result<Header, Box<dyn Error>> {
+        let hash = self
+            .block_hash(height)
+            .await?
+            .ok_or_else(|| format!("block #{} not found", height))?;
+        let header = self
+            .client()
+            .header(Some(hash))
+            .await?
+            .ok_or_else(|| format!("header of block #{} not found", height))?;
+        Ok(header)
+    }
+
+    async fn justification(&self, hash: H256) -> Result<Option<Justification>, Box<dyn Error>> {
+        let signed_block = self.client().block(Some(hash)).await?;
+        Ok(signed_block.and_then(|signed_block| signed_block.justification))
+    }
+
+    async fn authorities(&self, hash: H256) -> Result<AuthorityList, Box<dyn Error>> {
+        let authorities = self
+            .client()
+            .fetch_unhashed::<VersionedAuthorityList>(
+                StorageKey(GRANDPA_AUTHORITIES_KEY.to_vec()),
+                Some(hash),
+            )
+            .await?
+            .map(|versioned| versioned.into())
+            .ok_or_else(|| format!("no GRANDPA authorities at {:?}", hash))?;
+        Ok(authorities)
+    }
+
+    async fn client_state(
+        &self,
+        identifier: H256,
+        hash: Option<H256>,
+    ) -> Result<ClientState, Box<dyn Error>> {
+        Ok(self.client().clients(identifier, hash).await?)
+    }
+
+    async fn consensus_state(
+        &self,
+        identifier: H256,
+        height: u32,
+        hash: Option<H256>,
+    ) -> Result<Option<ConsensusState>, Box<dyn Error>> {
+        let consensus_states = ibc::ConsensusStatesStore::<Runtime> {
+            key: (identifier, height),
+            _runtime: Default::default(),
+        };
+        Ok(self.client().fetch(&consensus_states, hash).await?)
+    }
+
+    async fn connection_end(
+        &self,
+        identifier: H256,
+        hash: Option<H256>,
+    ) -> Result<ConnectionEnd, Box<dyn Error>> {
+        Ok(self.client().connections(identifier, hash).await?)
+    }
+
+    async fn channel_end(
+        &self,
+        port: Vec<u8>,
+        channel: H256,
+        hash: Option<H256>,
+    ) -> Result<ChannelEnd, Box<dyn Error>> {
+        Ok(self.client().channels((port, channel), hash).await?)
+    }
+
+    async fn packets(&self, hash: H256) -> Result<Vec<(Vec<u8>, H256, u64)>, Box<dyn Error>> {
+        // Keys of the map are the prefix of the map followed by its hashed key, which ends with
+        // the key itself as `Packets` is hashed with `Blake2_128Concat`.
+        let client = self.client();
+        let prefix = client
+            .metadata()
+            .module(ibc::PacketsStore::<Runtime>::MODULE)?
+            .storage(ibc::PacketsStore::<Runtime>::FIELD)?
+            .prefix();
+        let mut packets = vec![];
+        let mut start_key = None;
+        loop {
+            let keys = client
+                .fetch_keys::<ibc::PacketsStore<Runtime>>(KEYS_PAGE_SIZE, start_key, Some(hash))
+                .await?;
+            for key in &keys {
+                let mut encoded = key
+                    .0
+                    .get(prefix.0.len() + 16..)
+                    .ok_or("packet commitment key too short")?;
+                packets.push(<(Vec<u8>, H256, u64)>::decode(&mut encoded)?);
+            }
+            if keys.len() < KEYS_PAGE_SIZE as usize {
+                break;
+            }
+            start_key = keys.last().cloned();
+        }
+        packets.sort_unstable();
+        Ok(packets)
+    }
+
+    async fn packet_commitment(
+        &self,
+        port: Vec<u8>,
+        channel: H256,
+        sequence: u64,
+        hash: H256,
+    ) -> Result<Option<H256>, Box<dyn Error>> {
+        let packets = ibc::PacketsStore::<Runtime> {
+            key: (port, channel, sequence),
+            _runtime: Default::default(),
+        };
+        Ok(self.client().fetch(&packets, Some(hash)).await?)
+    }
+
+    async fn acknowledgement(
+        &self,
+        port: Vec<u8>,
+        channel: H256,
+        sequence: u64,
+        hash: H256,
+    ) -> Result<Option<H256>, Box<dyn Error>> {
+        let acknowledgements = ibc::AcknowledgementsStore::<Runtime> {
+            key: (port, channel, sequence),
+            _runtime: Default::default(),
+        };
+        Ok(self.client().fetch(&acknowledgements, Some(hash)).await?)
+    }
+
+    fn storage_key(&self, item: &StorageItem) -> Result<Vec<u8>, Box<dyn Error>> {
+        let client = self.client();
+        let metadata = client.metadata();
+        let key = match item.clone() {
+            StorageItem::ConsensusState(identifier, height) => {
+                ibc::ConsensusStatesStore::<Runtime> {
+                    key: (identifier, height),
+                    _runtime: Default::default(),
+                }
+                .key(metadata)?
+            }
+            StorageItem::Connection(identifier) => ibc::ConnectionsStore::<Runtime> {
+                key: identifier,
+                _runtime: Default::default(),
+            }
+            .key(metadata)?,
+            StorageItem::Channel(port, channel) => ibc::ChannelsStore::<Runtime> {
+                key: (port, channel),
+                _runtime: Default::default(),
+            }
+            .key(metadata)?,
+            StorageItem::Packet(port, channel, sequence) => ibc::PacketsStore::<Runtime> {
+                key: (port, channel, sequence),
+                _runtime: Default::default(),
+            }
+            .key(metadata)?,
+            StorageItem::Acknowledgement(port, channel, sequence) => {
+                ibc::AcknowledgementsStore::<Runtime> {
+                    key: (port, channel, sequence),
+                    _runtime: Default::default(),
+                }
+                .key(metadata)?
+            }
+            StorageItem::GrandpaAuthorities => StorageKey(GRANDPA_AUTHORITIES_KEY.to_vec()),
+        };
+        Ok(key.0)
+    }
+
+    async fn read_proof(
+        &self,
+        item: StorageItem,
+        hash: H256,
+    ) -> Result<StorageProof, Box<dyn Error>> {
+        let key = StorageKey(self.storage_key(&item)?);
+        let proof = self.client().read_proof(vec![key], Some(hash)).await?;
+        Ok(StorageProof::new(
+            proof.proof.into_iter().map(|b| b.0).collect(),
+        ))
+    }
+
+    async fn ibc_events(&self, hash: H256) -> Result<Vec<IbcEvent>, Box<dyn Error>> {
+        let subscribed = {
+            let mut events = self.events.lock().unwrap();
+            let subscribed = events.is_some();
+            let events = events.get_or_insert_with(VecDeque::new);
+            if let Some((_, events)) = events.iter().find(|(block, _)| *block == hash) {
+                return Ok(events.clone());
+            }
+            subscribed
+        };
+        if !subscribed {
+            async_std::task::spawn(self.clone().cache_ibc_events());
+        }
+        // The subscription only announces the blocks imported from then on, so the blocks the
+        // relayer catches up on after a restart or a reconnection, and those evicted from the
+        // cache or not announced yet, are read from storage.
+        let client = self.client();
+        let key = client
+            .metadata()
+            .module("System")?
+            .storage("Events")?
+            .plain()?
+            .key();
+        let decoder = events_decoder(&client)?;
+        let mut events = vec![];
+        for change_set in client.query_storage(vec![key], hash, Some(hash)).await? {
+            events.extend(decode_ibc_events(&decoder, &change_set)?);
+        }
+        Ok(events)
+    }
+
+    async fn unfinalized_ibc_events(&self) -> EventStream {
+        let headers = match self.client().subscribe_blocks().await {
+            Ok(headers) => headers,
+            Err(e) => {
+                warn!(
+                    "[{}] failed to subscribe to new heads; error = {}",
+                    self.name, e
+                );
+                return stream::empty().boxed();
+            }
+        };
+        stream::unfold((self.clone(), headers), |(chain, mut headers)| async move {
+            let header: Header = headers.next().await;
+            let events = match chain.ibc_events(header.hash()).await {
+                Ok(events) => events,
+                Err(e) => {
+                    warn!(
+                        "[{}] failed to read the events of #{}; error = {}",
+                        chain.name, header.number, e
+                    );
+                    vec![]
+                }
+            };
+            Some(((header, events), (chain, headers)))
+        })
+        .boxed()
+    }
+
+    fn submit(&self, path: &str, datagram: Datagram) -> Submission {
+        let queue = match self.queue.as_ref() {
+            Some(queue) => queue,
+            None => return future::ready(Err(format!("no signer for {}", self.name))).boxed(),
+        };
+        let (sender, receiver) = oneshot::channel();
+        let queued = Queued {
+            path: path.to_string(),
+            datagram,
+            outcome: sender,
+        };
+        if queue.unbounded_send(queued).is_err() {
+            return future::ready(Err(format!("submitter of {} stopped", self.name))).boxed();
+        }
+        let name = self.name.clone();
+        receiver
+            .map(move |outcome| {
+                outcome.unwrap_or_else(|_| Err(format!("submitter of {} stopped", name)))
+            })
+            .boxed()
+    }
+}
+
+/// The decoder of the events of the chain, with the types of the IBC module registered.
+fn events_decoder(client: &Client<Runtime>) -> Result<EventsDecoder<Runtime>, Box<dyn Error>> {
+    let mut decoder = EventsDecoder::<Runtime>::new(client.metadata().clone());
+    decoder.with_ibc();
+    decoder.register_type_size::<H256>("H256")?;
+    Ok(decoder)
+}
+
+/// Decodes the IBC events of a change of `System.Events`.
+fn decode_ibc_events(
+    decoder: &EventsDecoder<Runtime>,
+    change_set: &StorageChangeSet<H256>,
+) -> Result<Vec<IbcEvent>, Box<dyn Error>> {
+    let mut events = vec![];
+    for (_key, data) in &change_set.changes {
+        if let Some(data) = data {
+            for (_phase, raw) in decoder.decode_events(&mut &data.0[..])? {
+                if let Some(event) = decode_ibc_event(&raw)? {
+                    events.push(event);
+                }
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// Decodes the IBC events the relayer acts on, leaving out the others.
+fn decode_ibc_event(raw: &RawEvent) -> Result<Option<IbcEvent>, codec::Error> {
+    if raw.module != SendPacketEvent::<Runtime>::MODULE {
+        return Ok(None);
+    }
+    let event = if raw.variant == SendPacketEvent::<Runtime>::EVENT {
+        let event = SendPacketEvent::<Runtime>::decode(&mut &raw.data[..])?;
+        pallet_ibc::RawEvent::SendPacket(
+            event.sequence,
+            event.data,
+            event.timeout_height,
+            event.source_port,
+            event.source_channel,
+            event.dest_port,
+            event.dest_channel,
+        )
+    } else if raw.variant == RecvPacketEvent::<Runtime>::EVENT {
+        let event = RecvPacketEvent::<Runtime>::decode(&mut &raw.data[..])?;
+        pallet_ibc::RawEvent::RecvPacket(
+            event.sequence,
+            event.data,
+            event.timeout_height,
+            event.source_port,
+            event.source_channel,
+            event.dest_port,
+            event.dest_channel,
+            event.acknowledgement,
+        )
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(event))
+}